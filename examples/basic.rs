@@ -7,12 +7,11 @@ extern crate log;
 #[macro_use]
 extern crate edge;
 
-#[macro_use]
-extern crate lazy_static;
-
-use edge::{json, Edge, Router, Cookie, Request, Response, Result, Action, Status, stream};
+use edge::{json, AccessLog, Edge, LogFormat, Router, Cookie, Request, Response, Result, Action, Status, stream, event_stream, send_json};
+use edge::{Message, WebSocket};
 use edge::header::AccessControlAllowOrigin;
 
+use std::io;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
@@ -24,15 +23,11 @@ struct MyApp {
     counter: Arc<AtomicUsize>
 }
 
-lazy_static! {
-    static ref COUNTER: Arc<AtomicUsize> = Default::default();
-}
-
-impl Default for MyApp {
-    fn default() -> MyApp {
-        debug!("MyApp::default");
+impl From<Arc<AtomicUsize>> for MyApp {
+    fn from(counter: Arc<AtomicUsize>) -> MyApp {
+        debug!("MyApp::from");
         MyApp {
-            counter: COUNTER.clone()
+            counter: counter
         }
     }
 }
@@ -65,7 +60,7 @@ This is a list:
         ok!("hello", data)
     }
 
-    fn counter(&mut self, req: &Request, res: &mut Response) -> Result {
+    fn counter(&mut self, req: &Request, _res: &mut Response) -> Result {
         let mut cookies = req.cookies();
         let name = cookies.find(|cookie| cookie.name == "name")
             .map_or("nope", |cookie| &cookie.value);
@@ -75,12 +70,11 @@ This is a list:
         data.insert("name", json::to_value(name));
         let cnt = self.counter.load(Ordering::Relaxed);
         data.insert("counter", json::to_value(&cnt));
-        res.content_type("application/json");
-        ok!(json::to_value(&data))
+        send_json(&data)
     }
 
     fn login(&mut self, req: &Request, res: &mut Response) -> Result {
-        let form = try!(req.form().map_err(|e| (Status::BadRequest, e.to_string())));
+        let form = try!(req.form());
         if let Some(username) = form.get("username") {
             if username == "error with message" {
                 try!(Err((Status::BadRequest, "bad user name: error")));
@@ -118,6 +112,33 @@ This is a list:
         })
     }
 
+    fn events(&mut self, _req: &Request, _res: &mut Response) -> Result {
+        event_stream(|app: &mut Self, events| {
+            try!(events.retry(Duration::from_secs(5)).send());
+            for i in 0..60 {
+                let cnt = app.counter.load(Ordering::Relaxed);
+                try!(events.event("counter").id(&i.to_string()).data(&cnt.to_string()).send());
+                try!(events.sleep(Duration::from_secs(1)));
+            }
+            Ok(())
+        })
+    }
+
+    fn readme(&mut self, _req: &Request, _res: &mut Response) -> Result {
+        Ok(Action::Markdown("README.md".to_string()))
+    }
+
+    fn echo(&mut self, _req: &Request, ws: &mut WebSocket) -> io::Result<()> {
+        loop {
+            match try!(ws.recv()) {
+                Message::Text(text) => try!(ws.send_text(&text)),
+                Message::Binary(data) => try!(ws.send_binary(&data)),
+                Message::Close(_) => return Ok(()),
+                _ => ()
+            }
+        }
+    }
+
 }
 
 impl MyApp {
@@ -135,23 +156,30 @@ fn main() {
     env_logger::init().unwrap();
 
     let mut edge = Edge::new("0.0.0.0:3000");
-    let mut router = Router::new();
+    edge.access_log(AccessLog::to_log(LogFormat::Combined));
+    let counter = Arc::new(AtomicUsize::new(0));
+    let mut router = Router::with_state(counter);
     router.get("/", MyApp::home);
     router.get("/hello/:first_name/:last_name", MyApp::hello);
     router.get("/counter", MyApp::counter);
 
     router.get("/redirect", MyApp::redirect);
     router.get("/streaming", MyApp::streaming);
+    router.get("/events", MyApp::events);
+    router.websocket("/echo", MyApp::echo);
 
     router.post("/login", MyApp::login);
 
     router.get_static("/static", files);
+    router.get("/readme", MyApp::readme);
 
     // registers middleware
     router.add_middleware(MyApp::before);
 
-    // registers view views/hello.hbs
-    edge.register_template("hello");
+    // registers views views/layout.hbs and views/hello.hbs
+    edge.register_template("layout").unwrap();
+    edge.set_layout("layout");
+    edge.register_template("hello").unwrap();
 
     edge.mount("/", router);
     edge.start().unwrap();
@@ -8,6 +8,7 @@ extern crate edge;
 extern crate rusqlite;
 
 use edge::{json, Edge, Router, Request, Response, Result, Status};
+use edge::Error as EdgeError;
 use edge::json::value::ToJson;
 
 use rusqlite::Connection;
@@ -34,16 +35,16 @@ impl Db {
             user_id = "1".to_string();
         }
 
-        let connection = try!(Connection::open("db/demo.db").map_err(|e| (Status::InternalServerError, e.to_string())));
+        let connection = try!(Connection::open("db/demo.db").map_err(|e| EdgeError::wrap(Status::InternalServerError, e)));
         let user = try!(connection.query_row("SELECT * FROM users WHERE user_id = ?", &[&user_id], |row|
             User {
                 id: row.get(0),
                 name: row.get(1)
             }
-        ).map_err(|e| (Status::InternalServerError, match e {
-            Error::QueryReturnedNoRows => format!("no user known with id {}", user_id),
-            _ => format!("error when requesting user: {}", e)
-        })));
+        ).map_err(|e| match e {
+            Error::QueryReturnedNoRows => EdgeError::from((Status::NotFound, format!("no user known with id {}", user_id))),
+            _ => EdgeError::wrap(Status::InternalServerError, e)
+        }));
 
         let mut data = BTreeMap::new();
         data.insert("id", json::to_value(&user.id));
@@ -78,6 +79,8 @@ fn main() {
     let mut router = Router::new();
     router.get("/:user_id", Db::home);
     edge.mount("/", router);
-    edge.register_template("db");
+    edge.register_template("layout").unwrap();
+    edge.set_layout("layout");
+    edge.register_template("db").unwrap();
     edge.start().unwrap();
 }
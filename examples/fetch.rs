@@ -72,6 +72,8 @@ fn main() {
     router.get("/fetch", Fetch::fetch);
     edge.mount("/api/v1", router);
 
-    edge.register_template("fetch");
+    edge.register_template("layout").unwrap();
+    edge.set_layout("layout");
+    edge.register_template("fetch").unwrap();
     edge.start().unwrap();
 }
@@ -0,0 +1,253 @@
+//! Access log, recording one line per request.
+//!
+//! Lines are written in Common Log Format, Combined Log Format, or as JSON objects (one per line),
+//! either to the `log` crate (target `edge::access`, level info) or to a file.
+//! The remote host is the IP address of the client on plain HTTP listeners;
+//! it is not known on HTTPS and Unix domain socket listeners, and logged as `-`.
+//!
+//! A log file is reopened when the process receives SIGHUP, so that it can be rotated
+//! by moving it and sending the signal, as with `logrotate`.
+//!
+//! Entries are formatted and written by a dedicated thread, so that serving requests never waits
+//! on the log; entries still pending are written when the access log is dropped.
+
+use chrono::Local;
+
+use hyper::header::{Referer, UserAgent};
+use hyper::server::Request as HttpRequest;
+use hyper::status::StatusCode as Status;
+
+use serde_json::value::Value;
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The format of access log lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `host ident user [time] "request" status bytes`
+    Common,
+
+    /// Common Log Format, followed by `"referer" "user-agent"`
+    Combined,
+
+    /// A JSON object per line, which also includes the duration of the request in milliseconds
+    Json
+}
+
+enum Target {
+    Log,
+    File(PathBuf, File)
+}
+
+/// An access log, see `Edge::access_log`.
+pub struct AccessLog {
+    sender: Option<Mutex<Sender<Entry>>>,
+    writer: Option<JoinHandle<()>>,
+    is_file: bool
+}
+
+impl AccessLog {
+    /// Creates an access log that writes to the `log` crate.
+    pub fn to_log(format: LogFormat) -> AccessLog {
+        AccessLog::new(format, Target::Log)
+    }
+
+    /// Creates an access log that appends to the file at the given path.
+    pub fn to_file<P: AsRef<Path>>(format: LogFormat, path: P) -> io::Result<AccessLog> {
+        let path = path.as_ref().to_path_buf();
+        let file = try!(open(&path));
+        Ok(AccessLog::new(format, Target::File(path, file)))
+    }
+
+    fn new(format: LogFormat, target: Target) -> AccessLog {
+        let is_file = match target {
+            Target::Log => false,
+            Target::File(_, _) => true
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || write_entries(format, target, receiver));
+        AccessLog {
+            sender: Some(Mutex::new(sender)),
+            writer: Some(writer),
+            is_file: is_file
+        }
+    }
+
+    /// Returns `true` if this access log writes to a file.
+    pub fn is_file(&self) -> bool {
+        self.is_file
+    }
+
+    /// Makes log files reopen before their next entry is written, for example after they have been moved.
+    ///
+    /// This is what happens on SIGHUP when signals are handled.
+    pub fn reopen(&self) {
+        REOPEN.store(true, Ordering::SeqCst);
+    }
+
+    /// Sends the given entry to the writer thread.
+    pub fn write(&self, mut entry: Entry) {
+        entry.duration = entry.start.elapsed();
+        if let Some(ref sender) = self.sender {
+            if sender.lock().unwrap().send(entry).is_err() {
+                error!("could not write to access log: writer thread is gone");
+            }
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // closing the channel makes the writer exit once pending entries are written
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes the entries received until the channel is closed.
+fn write_entries(format: LogFormat, mut target: Target, entries: Receiver<Entry>) {
+    for entry in entries {
+        let line = format_entry(format, &entry);
+        match target {
+            Target::Log => info!(target: "edge::access", "{}", line),
+            Target::File(ref path, ref mut file) => {
+                if REOPEN.swap(false, Ordering::SeqCst) {
+                    info!("reopening access log {}", path.display());
+                    match open(path) {
+                        Ok(reopened) => *file = reopened,
+                        Err(e) => error!("could not reopen access log {}: {}", path.display(), e)
+                    }
+                }
+
+                if let Err(e) = writeln!(file, "{}", line) {
+                    error!("could not write to access log {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+fn format_entry(format: LogFormat, entry: &Entry) -> String {
+    let bytes = if entry.bytes == 0 { "-".to_string() } else { entry.bytes.to_string() };
+    let status = entry.status.map_or(0, |status| status.to_u16());
+    let millis = entry.duration.as_secs() * 1000 + (entry.duration.subsec_nanos() / 1_000_000) as u64;
+
+    match format {
+        LogFormat::Common | LogFormat::Combined => {
+            let mut line = format!("{} - - [{}] \"{} {} {}\" {} {}",
+                entry.remote_addr.as_ref().map_or("-", String::as_str),
+                entry.time, entry.method, escape(&entry.target), entry.version, status, bytes);
+            if format == LogFormat::Combined {
+                line.push_str(&format!(" \"{}\" \"{}\"",
+                    entry.referer.as_ref().map_or("-".to_string(), |referer| escape(referer)),
+                    entry.user_agent.as_ref().map_or("-".to_string(), |user_agent| escape(user_agent))));
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut object = BTreeMap::new();
+            object.insert("time".to_string(), Value::String(entry.time.clone()));
+            object.insert("remote_addr".to_string(), entry.remote_addr.clone().map_or(Value::Null, Value::String));
+            object.insert("method".to_string(), Value::String(entry.method.clone()));
+            object.insert("path".to_string(), Value::String(entry.target.clone()));
+            object.insert("version".to_string(), Value::String(entry.version.clone()));
+            object.insert("status".to_string(), Value::U64(status as u64));
+            object.insert("bytes".to_string(), Value::U64(entry.bytes));
+            object.insert("duration_ms".to_string(), Value::U64(millis));
+            object.insert("referer".to_string(), entry.referer.clone().map_or(Value::Null, Value::String));
+            object.insert("user_agent".to_string(), entry.user_agent.clone().map_or(Value::Null, Value::String));
+            Value::Object(object).to_string()
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Escapes quotes and backslashes, so that a quoted field cannot be broken.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// What is recorded about a request.
+pub struct Entry {
+    start: Instant,
+    time: String,
+    remote_addr: Option<String>,
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    status: Option<Status>,
+    bytes: u64,
+    duration: Duration
+}
+
+impl Entry {
+    /// Creates an entry for the given request received from the given address (if known), starting now.
+    pub fn new(req: &HttpRequest, remote_addr: Option<SocketAddr>) -> Entry {
+        Entry {
+            start: Instant::now(),
+            time: Local::now().format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+            remote_addr: remote_addr.map(|addr| addr.ip().to_string()),
+            method: req.method().to_string(),
+            target: req.uri().to_string(),
+            version: req.version().to_string(),
+            referer: req.headers().get::<Referer>().map(|referer| referer.to_string()),
+            user_agent: req.headers().get::<UserAgent>().map(|user_agent| user_agent.to_string()),
+            status: None,
+            bytes: 0,
+            duration: Duration::from_secs(0)
+        }
+    }
+
+    /// Returns the status of the response, if one was sent.
+    pub fn status(&self) -> Option<Status> {
+        self.status
+    }
+
+    /// Records the status of the response.
+    pub fn set_status(&mut self, status: Status) {
+        self.status = Some(status);
+    }
+
+    /// Records that the given number of bytes of the response body were sent.
+    pub fn add_bytes(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+static REOPEN: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[cfg(unix)]
+extern "C" fn on_sighup(_: ::libc::c_int) {
+    REOPEN.store(true, Ordering::SeqCst);
+}
+
+/// Installs a handler for SIGHUP that reopens log files.
+#[cfg(unix)]
+pub fn install_signal_handler() {
+    use libc;
+
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handler() {
+}
@@ -0,0 +1,87 @@
+//! Static assets embedded in the binary.
+//!
+//! The asset table is built at compile time with `include_bytes!`, so that an application
+//! can be deployed as a single file:
+//!
+//! ```ignore
+//! static FILES: &'static [(&'static str, &'static [u8])] = &[
+//!     ("css/app.css", include_bytes!("../web/css/app.css")),
+//!     ("js/app.js", include_bytes!("../web/js/app.js"))
+//! ];
+//!
+//! lazy_static! {
+//!     static ref ASSETS: Assets = Assets::new(FILES);
+//! }
+//!
+//! fn files(req: &Request, _res: &mut Response) -> Result {
+//!     ASSETS.send(&req.path()[1..].join("/"))
+//! }
+//! ```
+//!
+//! Embedded files are sent like files read from disk: the Content-Type is guessed from
+//! the extension, and the ETag allows clients to revalidate their cached copy.
+//!
+//! `Assets::new` computes ETags from the contents when the table is created, at startup.
+//! Rust cannot hash the contents during compilation, so to avoid this cost, a build script can
+//! compute the ETags with `Assets::etag` and generate a table for `Assets::with_etags` instead.
+
+use hyper::status::StatusCode as Status;
+
+use sha1::Sha1;
+
+use response::{Action, Result};
+
+use std::collections::BTreeMap;
+
+/// A file embedded in the binary.
+#[derive(Clone, Debug)]
+pub struct EmbeddedFile {
+    pub path: &'static str,
+    pub content: &'static [u8],
+    pub etag: String
+}
+
+/// A table of embedded files, indexed by their path.
+pub struct Assets {
+    files: BTreeMap<&'static str, EmbeddedFile>
+}
+
+impl Assets {
+    /// Creates an asset table from the given paths and contents.
+    pub fn new(files: &[(&'static str, &'static [u8])]) -> Assets {
+        Assets {
+            files: files.iter().map(|&(path, content)|
+                (path, EmbeddedFile { path: path, content: content, etag: Assets::etag(content) })
+            ).collect()
+        }
+    }
+
+    /// Creates an asset table from the given paths, contents, and ETags computed beforehand with `Assets::etag`.
+    pub fn with_etags(files: &[(&'static str, &'static [u8], &'static str)]) -> Assets {
+        Assets {
+            files: files.iter().map(|&(path, content, etag)|
+                (path, EmbeddedFile { path: path, content: content, etag: etag.to_string() })
+            ).collect()
+        }
+    }
+
+    /// Returns the ETag of the given content, as used by `new`.
+    pub fn etag(content: &[u8]) -> String {
+        let mut sha1 = Sha1::new();
+        sha1.update(content);
+        sha1.digest().to_string()[..16].to_string()
+    }
+
+    /// Returns the embedded file at the given path (if any).
+    pub fn get(&self, path: &str) -> Option<&EmbeddedFile> {
+        self.files.get(path)
+    }
+
+    /// Sends the embedded file at the given path, or 404 Not Found if there is none.
+    pub fn send(&self, path: &str) -> Result {
+        match self.get(path) {
+            Some(file) => Ok(Action::SendEmbedded(file.clone())),
+            None => Err(Status::NotFound.into())
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Error handlers, used to customize the response sent for an error.
+//!
+//! Error handlers apply to errors returned by handlers, to routing errors (404 Not Found,
+//! 405 Method Not Allowed), and to malformed requests (400 Bad Request).
+
+use hyper::status::StatusCode as Status;
+
+use serde_json::value as json;
+
+use response::{Action, Error, Response};
+
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+
+/// An error handler turns an error into an action that produces the response.
+pub type ErrorHandler = Box<Fn(&Error, &mut Response) -> Action + Send + Sync>;
+
+/// Error handlers registered on an Edge application.
+pub struct ErrorHandlers {
+    by_status: BTreeMap<u16, ErrorHandler>,
+    default: Option<ErrorHandler>,
+    debug: bool
+}
+
+impl ErrorHandlers {
+    pub fn new() -> ErrorHandlers {
+        ErrorHandlers {
+            by_status: BTreeMap::new(),
+            default: None,
+            debug: false
+        }
+    }
+
+    /// When debug is enabled, server errors caused by another error are shown on a debug page,
+    /// unless a handler is registered for them.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    pub fn insert(&mut self, status: Status, handler: ErrorHandler) {
+        self.by_status.insert(status.to_u16(), handler);
+    }
+
+    pub fn set_default(&mut self, handler: ErrorHandler) {
+        self.default = Some(handler);
+    }
+
+    /// Returns the action for the given error.
+    ///
+    /// Calls the handler registered for the status of this error, or the catch-all handler.
+    /// If there is none, the error message (if any) is sent as plain text.
+    /// In debug mode, a server error (5xx) that has a cause and no handler is shown on a debug page instead.
+    pub fn handle(&self, error: &Error, response: &mut Response) -> Action {
+        response.status(error.status);
+        response.headers.extend(error.headers.iter());
+
+        let handler = self.by_status.get(&error.status.to_u16()).or(self.default.as_ref());
+        if let Some(handler) = handler {
+            handler(error, response)
+        } else if self.debug && error.status.is_server_error() && error.cause().is_some() {
+            debug_page(error, response)
+        } else {
+            match error.message {
+                None => Action::End(None),
+                Some(ref message) => {
+                    response.content_type("text/plain; charset=UTF-8");
+                    Action::Send(message.as_bytes().to_vec())
+                }
+            }
+        }
+    }
+}
+
+/// Returns the data describing the given error: status, reason and message.
+fn error_data(error: &Error) -> BTreeMap<String, json::Value> {
+    let mut data = BTreeMap::new();
+    data.insert("status".to_string(), json::Value::U64(error.status.to_u16() as u64));
+    data.insert("reason".to_string(), json::Value::String(error.status.canonical_reason().unwrap_or("").to_string()));
+    if let Some(ref message) = error.message {
+        data.insert("message".to_string(), json::Value::String(message.to_string()));
+    }
+    data
+}
+
+/// Returns an error handler that renders the template with the given name.
+///
+/// The template is given the `status` code, its `reason` phrase, and the error `message` (if any).
+pub fn error_template(name: &str) -> ErrorHandler {
+    let name = name.to_string();
+    Box::new(move |error, _response| {
+        Action::Render(name.clone(), json::Value::Object(error_data(error)))
+    })
+}
+
+/// Returns an error handler that sends a JSON problem document, as defined by RFC 7807.
+pub fn problem_json() -> ErrorHandler {
+    Box::new(|error, response| {
+        let mut problem = BTreeMap::new();
+        problem.insert("type".to_string(), json::Value::String("about:blank".to_string()));
+        problem.insert("title".to_string(), json::Value::String(error.status.canonical_reason().unwrap_or("").to_string()));
+        problem.insert("status".to_string(), json::Value::U64(error.status.to_u16() as u64));
+        if let Some(ref message) = error.message {
+            problem.insert("detail".to_string(), json::Value::String(message.to_string()));
+        }
+
+        response.content_type("application/problem+json; charset=utf-8");
+        Action::Json(json::Value::Object(problem).to_string().into_bytes())
+    })
+}
+
+/// Returns a page showing the given error and its chain of causes.
+///
+/// This page reveals internal details, so it is only used in development mode.
+fn debug_page(error: &Error, response: &mut Response) -> Action {
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n", escape(&error.status.to_string()));
+    if let Some(ref message) = error.message {
+        html.push_str(&format!("<p>{}</p>\n", escape(message)));
+    }
+
+    html.push_str("<h2>Caused by</h2>\n<ol>\n");
+    let mut cause = error.cause();
+    while let Some(err) = cause {
+        html.push_str(&format!("<li><pre>{}</pre></li>\n", escape(&format!("{:?}", err))));
+        cause = err.cause();
+    }
+    html.push_str("</ol>\n</body>\n</html>\n");
+
+    response.content_type("text/html; charset=UTF-8");
+    Action::Send(html.into_bytes())
+}
+
+/// Escapes the given text for inclusion in HTML.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
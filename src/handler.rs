@@ -1,35 +1,54 @@
-use handlebars::Handlebars;
-
 use hyper::{Control, Decoder, Encoder, Next};
 use hyper::HttpVersion::{Http09, Http10, Http11};
 
 use hyper::error::Error as HyperError;
-use hyper::header::{ContentLength, ContentType, Encoding, TransferEncoding};
+use hyper::header::{Allow, CacheControl, CacheDirective, Connection, ContentLength, ContentType, ETag, Encoding, EntityTag, Host, IfNoneMatch, TransferEncoding};
+use hyper::method::Method;
 use hyper::method::Method::{Connect, Delete, Get, Head, Trace};
-use hyper::net::HttpStream;
+use hyper::net::{HttpStream, Transport};
 use hyper::server::{Handler, Request as HttpRequest, Response as HttpResponse};
 use hyper::status::StatusCode as Status;
 
-use scoped_pool::Scope;
+use scoped_pool::Pool;
 
 use serde_json::value as json;
 
 use url::Url;
 
+use access_log::Entry;
 use buffer::Buffer;
+use errors::ErrorHandlers;
+use helpers;
+use markdown;
 use request::{self, Request};
-use response::{self, Response, Result, Action};
+use response::{self, Response, Result, Action, Error, Layout};
 use router::{Callback, RouterAny};
+use server::{self, Endpoint, Peer, PeerStream, ServerHandle};
+use sse;
+use templates::Templates;
+use websocket;
 
 use crossbeam::sync::chase_lev::{deque, Steal, Stealer, Worker};
 
 use std::any::Any;
-use std::io::{self, Write};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::result;
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 enum Reply {
     Initial(Response, Option<Buffer>),
     Buffer(Buffer),
-    End
+    End,
+    Abort
 }
 
 enum Body {
@@ -40,7 +59,10 @@ enum Body {
 
 struct Stream {
     worker: Worker<Reply>,
-    control: Control
+    control: Control,
+    closed: Arc<AtomicBool>,
+    handle: ServerHandle,
+    aborted: bool
 }
 
 fn notify(control: &Control) {
@@ -51,6 +73,13 @@ fn notify(control: &Control) {
 
 impl Write for Stream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "connection closed by client"));
+        }
+        if server::is_closing(&self.handle) {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "connection closed by server shutdown"));
+        }
+
         self.worker.push(Reply::Buffer(buf.to_vec().into()));
         notify(&self.control);
         Ok(buf.len())
@@ -63,78 +92,238 @@ impl Write for Stream {
 
 impl Drop for Stream {
     fn drop(&mut self) {
-        self.worker.push(Reply::End);
+        self.worker.push(if self.aborted { Reply::Abort } else { Reply::End });
         notify(&self.control);
     }
 }
 
+thread_local!(static IN_HANDLER: Cell<bool> = Cell::new(false));
+
+static PANIC_HOOK: Once = ONCE_INIT;
+
+/// Installs a panic hook that logs panics occurring in handlers with their location.
+///
+/// Panics occurring outside of handlers are passed to the previous hook.
+pub fn set_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if IN_HANDLER.with(|in_handler| in_handler.get()) {
+                let payload = info.payload();
+                let message = match payload.downcast_ref::<&'static str>() {
+                    Some(message) => *message,
+                    None => payload.downcast_ref::<String>().map_or("Box<Any>", |message| &message[..])
+                };
+
+                match info.location() {
+                    Some(location) => error!("handler panicked at '{}', {}:{}", message, location.file(), location.line()),
+                    None => error!("handler panicked at '{}'", message)
+                }
+            } else {
+                previous(info);
+            }
+        }));
+    });
+}
+
+/// Calls the given closure, returns `None` if it panicked.
+fn catch_panic<F: FnOnce() -> R, R>(f: F) -> Option<R> {
+    IN_HANDLER.with(|in_handler| in_handler.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    IN_HANDLER.with(|in_handler| in_handler.set(false));
+    result.ok()
+}
+
+/// A WebSocket handshake that is completed once the connection is taken over from Hyper.
+struct PendingUpgrade {
+    routers: Arc<Vec<RouterAny>>,
+    index: usize,
+    callback: Arc<Callback>,
+    request: Request,
+    accept: String,
+    timeout: Option<Duration>,
+    handle: ServerHandle
+}
+
 /// a handler that lasts only the time of a request
 /// scope outlives handler
-pub struct EdgeHandler<'handler, 'scope: 'handler> {
-    scope: &'handler Scope<'scope>,
-    base_url: &'handler Url,
-    routers: &'scope [RouterAny],
+pub struct EdgeHandler<'scope> {
+    pool: &'scope Pool,
+    endpoint: &'scope Endpoint,
+    routers: &'scope Arc<Vec<RouterAny>>,
     request: Option<Request>,
     is_head_request: bool,
+    is_safe_request: bool,
+    reading_body: bool,
+    waiting_handler: bool,
+    timeout_reply: Option<(Response, Option<Buffer>)>,
+    if_none_match: Option<IfNoneMatch>,
+    access: Option<Entry>,
+    peer: Option<Rc<Peer>>,
     buffer: Option<Buffer>,
 
-    handlebars: &'scope Handlebars,
+    templates: &'scope Arc<Templates>,
+    error_handlers: &'scope Arc<ErrorHandlers>,
+    handle: &'scope ServerHandle,
+    started: bool,
     control: Control,
     worker: Option<Worker<Reply>>,
     stealer: Stealer<Reply>,
-    streaming: bool
+    streaming: bool,
+    heartbeat: Option<Duration>,
+    closed: Arc<AtomicBool>,
+    upgrade: Option<PendingUpgrade>
 }
 
-impl<'handler, 'scope> EdgeHandler<'handler, 'scope> {
-    pub fn new(scope: &'handler Scope<'scope>, base_url: &'handler Url, routers: &'scope [RouterAny], handlebars: &'scope Handlebars, control: Control) -> EdgeHandler<'handler, 'scope> {
+impl<'scope> EdgeHandler<'scope> {
+    pub fn new(pool: &'scope Pool, endpoint: &'scope Endpoint, routers: &'scope Arc<Vec<RouterAny>>, templates: &'scope Arc<Templates>, error_handlers: &'scope Arc<ErrorHandlers>, handle: &'scope ServerHandle, control: Control) -> EdgeHandler<'scope> {
         let (worker, stealer) = deque();
         EdgeHandler {
-            scope: scope,
-            base_url: base_url,
+            pool: pool,
+            endpoint: endpoint,
             routers: routers,
             request: None,
             is_head_request: false,
+            is_safe_request: false,
+            reading_body: false,
+            waiting_handler: false,
+            timeout_reply: None,
+            if_none_match: None,
+            access: None,
+            peer: None,
             buffer: None,
 
-            handlebars: handlebars,
+            templates: templates,
+            error_handlers: error_handlers,
+            handle: handle,
+            started: false,
             control: control,
             worker: Some(worker),
             stealer: stealer,
-            streaming: false
+            streaming: false,
+            heartbeat: None,
+            closed: Arc::new(AtomicBool::new(false)),
+            upgrade: None
         }
     }
 
     fn callback(&mut self) -> Next {
-        let mut worker = self.worker.take().unwrap();
         let mut req = self.request.take().unwrap();
 
-        let result = self.routers.iter().filter_map(|router|
-            if let Some(callback) = router.find_callback(&mut req) {
-                Some((router, callback))
+        if self.handle.is_shutting_down() {
+            let error = Error::new(Status::ServiceUnavailable, Some(Cow::Borrowed("Server is shutting down")));
+            return self.send_error(Response::new(), error);
+        }
+
+        let endpoint = self.endpoint;
+        if let Some(ref https_url) = endpoint.redirect {
+            return self.redirect(https_url, &req);
+        }
+
+        let result = self.routers.iter().enumerate().filter(|&(_, router)| router.serves(&endpoint.name)).filter_map(|(index, router)|
+            if let Some(route) = router.find_route(&mut req) {
+                Some((index, router, route))
             } else {
                 None
             }
         ).next();
 
-        if let Some((router, callback)) = result {
-            // add job to scoped pool
+        if let Some((index, router, route)) = result {
+            let callback = route.callback();
+            if let Callback::WebSocket(_) = *callback {
+                if !endpoint.websocket {
+                    let error = Error::new(Status::NotImplemented, Some(Cow::Borrowed("WebSocket is not supported on this listener")));
+                    return self.send_error(Response::new(), error);
+                }
+
+                return match websocket::check_handshake(&req) {
+                    Ok(_) if !server::websocket_queued(self.handle) => {
+                        // the connection would wait for a handler thread, or take the last one
+                        warn!("too many WebSocket connections or pending jobs, rejecting WebSocket {:?}", req.path());
+                        let mut response = Response::new();
+                        response.header_raw("Retry-After", "1");
+                        self.send_error(response, Error::new(Status::ServiceUnavailable, Some(Cow::Borrowed("Service Unavailable"))))
+                    }
+                    Ok(accept) => {
+                        // removing the connection gives us the transport in on_remove
+                        self.upgrade = Some(PendingUpgrade {
+                            routers: self.routers.clone(),
+                            index: index,
+                            callback: callback,
+                            request: req,
+                            accept: accept,
+                            timeout: endpoint.timeouts.websocket,
+                            handle: self.handle.clone()
+                        });
+                        Next::remove()
+                    }
+                    Err(msg) => self.bad_request(msg)
+                };
+            }
+
+            // too many jobs are waiting already, the client should try again later
+            if !server::job_queued(self.handle) {
+                warn!("too many pending jobs, rejecting {} {:?}", req.method(), req.path());
+                let mut response = Response::new();
+                response.header_raw("Retry-After", "1");
+                return self.send_error(response, Error::new(Status::ServiceUnavailable, Some(Cow::Borrowed("Service Unavailable"))));
+            }
+
+            // the handler must produce a response before the deadline (if any)
+            let timeout = router.timeout(route).or(endpoint.timeouts.handler);
+            if let Some(timeout) = timeout {
+                request::set_deadline(&mut req, Instant::now() + timeout);
+            }
+
+            // add job to the pool, which shares the routers, templates and error handlers
+            // so that the job does not hold up shutdown if it outlives the server
+            let mut worker = self.worker.take().unwrap();
             let ctrl = self.control.clone();
-            let handlebars = self.handlebars;
+            let closed = self.closed.clone();
+            let handle = self.handle.clone();
+            let routers = self.routers.clone();
+            let templates = self.templates.clone();
+            let error_handlers = self.error_handlers.clone();
+
+            self.pool.spawn(move || {
+                server::job_started(&handle);
+                let router = &routers[index];
 
-            self.scope.execute(move || {
                 let mut response = Response::new();
                 let mut boxed_app = router.new_instance();
                 let app = boxed_app.as_mut();
-                let result =
-                    match *callback {
-                        Callback::Instance(ref f) => {
-                            router.run_middleware(app, &mut req, &mut response);
-                            f(app, &req, &mut response)
-                        }
-                        Callback::Static(ref f) => f(&req, &mut response)
-                    };
+                let body = catch_panic(|| {
+                    let result =
+                        match *callback {
+                            Callback::Instance(ref f) => {
+                                router.run_middleware(app, &mut req, &mut response);
+                                f(app, &req, &mut response)
+                            }
+                            Callback::Static(ref f) => f(&req, &mut response),
+                            Callback::WebSocket(_) => unreachable!()
+                        };
+
+                    response::inherit_layout(&mut response, router.layout());
+                    process_handle_result(&mut response, result, &templates, &error_handlers)
+                });
+
+                let body = body.unwrap_or_else(|| {
+                    // discard whatever the handler did to the response
+                    response = Response::new();
+                    let error = Error::new(Status::InternalServerError, None);
+                    catch_panic(|| process_handle_result(&mut response, Err(error), &templates, &error_handlers)).unwrap_or_else(|| {
+                        // the error handler panicked too
+                        response = Response::new();
+                        response.status(Status::InternalServerError);
+                        Body::Empty
+                    })
+                });
+                if closed.load(Ordering::SeqCst) {
+                    // the deadline expired or the client is gone
+                    info!("discarding response to {} {:?}", req.method(), req.path());
+                    return;
+                }
 
-                let body = process_handle_result(&mut response, result, handlebars);
                 if let Body::Some(body) = body {
                     response.len(body.len() as u64);
                     worker.push(Reply::Initial(response, Some(body)));
@@ -146,118 +335,324 @@ impl<'handler, 'scope> EdgeHandler<'handler, 'scope> {
                     if let Body::Streaming(closure) = body {
                         let mut stream = Stream {
                             worker: worker,
-                            control: ctrl
+                            control: ctrl,
+                            closed: closed,
+                            handle: handle,
+                            aborted: false
                         };
-                        closure(app, &mut stream);
+
+                        if catch_panic(|| closure(app, &mut stream)).is_none() {
+                            // closes the connection so the client knows the response is incomplete
+                            stream.aborted = true;
+                        }
                     }
                 }
             });
 
             // and wait for it to notify us
-            Next::wait()
+            self.waiting_handler = true;
+            with_timeout(Next::wait(), timeout)
         } else {
-            //warn!("route not found for path {:?}", req.path())
+            // a route may exist for this path with another method
+            let mut allowed: Vec<Method> = Vec::new();
+            for method in self.routers.iter().filter(|router| router.serves(&endpoint.name)).flat_map(|router| router.allowed_methods(&req)) {
+                if !allowed.contains(&method) {
+                    allowed.push(method);
+                }
+            }
+
             let mut response = Response::new();
-            response.status(Status::NotFound).content_type("text/plain");
-            worker.push(Reply::Initial(response, Some(format!("not found: {:?}", req.path()).into_bytes().into())));
-            Next::write()
+            if allowed.is_empty() {
+                self.send_error(response, Error::new(Status::NotFound, Some(Cow::Borrowed("Not Found"))))
+            } else {
+                response.header(Allow(allowed));
+                self.send_error(response, Error::new(Status::MethodNotAllowed, Some(Cow::Borrowed("Method Not Allowed"))))
+            }
         }
     }
 
+    /// Redirects the given request to the same path and query on the HTTPS listener.
+    ///
+    /// The host name is taken from the Host header of the request, and the port from the HTTPS listener.
+    fn redirect(&mut self, https_url: &Url, req: &Request) -> Next {
+        let mut url = https_url.clone();
+        if let Some(host) = req.headers().get::<Host>() {
+            let _ = url.set_host(Some(host.hostname.as_str()));
+        }
+        if url.port() == Some(443) {
+            let _ = url.set_port(None);
+        }
+        if let Some(req_url) = req.url() {
+            url.set_path(req_url.path());
+            url.set_query(req_url.query());
+        }
+
+        // 301 makes clients change other methods to GET, 307 keeps the method and body
+        let mut response = Response::new();
+        if *req.method() == Get || *req.method() == Head {
+            response.status(Status::MovedPermanently);
+        } else {
+            response.status(Status::TemporaryRedirect);
+        }
+        response.location(url.to_string());
+        self.worker.as_mut().unwrap().push(Reply::Initial(response, None));
+        Next::write()
+    }
+
     fn bad_request(&mut self, message: &str) -> Next {
         error!("Bad Request: {}", message);
-        let mut response = Response::new();
-        response.status(Status::BadRequest).content_type("text/plain; charset=UTF-8");
-        self.worker.as_mut().unwrap().push(Reply::Initial(response, Some(message.to_string().into_bytes().into())));
+        self.send_error(Response::new(), Error::new(Status::BadRequest, Some(Cow::Owned(message.to_string()))))
+    }
+
+    /// Sends the response produced by the error handlers for the given error.
+    fn send_error(&mut self, response: Response, error: Error) -> Next {
+        let (response, body) = self.error_reply(response, error);
+        self.worker.as_mut().unwrap().push(Reply::Initial(response, body));
         Next::write()
     }
 
+    /// Returns the response and body produced by the error handlers for the given error.
+    ///
+    /// Error handlers run on the event loop thread, so a panic is caught and turned into a bare 500 response.
+    fn error_reply(&self, response: Response, error: Error) -> (Response, Option<Buffer>) {
+        let templates = self.templates;
+        let error_handlers = self.error_handlers;
+        catch_panic(move || {
+            let mut response = response;
+            let action = error_handlers.handle(&error, &mut response);
+            let body = match process_action(&mut response, action, templates) {
+                Ok(Body::Empty) => None,
+                Ok(Body::Some(body)) => {
+                    response.len(body.len() as u64);
+                    Some(body)
+                }
+                Ok(Body::Streaming(_)) => {
+                    error!("an error handler cannot stream its response");
+                    None
+                }
+                Err(error) => {
+                    error!("could not process action of error handler: {}", error);
+                    response = Response::new();
+                    response.status(Status::InternalServerError);
+                    None
+                }
+            };
+
+            (response, body)
+        }).unwrap_or_else(|| {
+            let mut response = Response::new();
+            response.status(Status::InternalServerError);
+            (response, None)
+        })
+    }
+
 }
 
 /// Matches the result to update the response and produce a body.
 ///
-/// If the result is Ok, converts the value into a HandleResult, and calls
-/// end/send/render/redirect depending on the type of result.
-/// Otherwise, if the result is Err, sets the status with the error message as content (if specified).
-/// as the body.
-fn process_handle_result(response: &mut Response, result: Result, handlebars: &Handlebars) -> Body {
-    match result {
-        Ok(handler) => {
-            match handler.into() {
-                Action::End(status) => {
-                    if let Some(status) = status {
-                        response.status(status);
-                    }
-                    Body::Empty
-                }
-                Action::Redirect(status, url) => {
-                    response.status(status);
-                    response.location(url);
-                    Body::Empty
-                }
-                Action::Render(name, json) => {
-                    let buffer = render(response, handlebars, &name, &json);
-                    Body::Some(buffer)
-                }
-                Action::Send(body) => {
-                    Body::Some(body.into())
-                }
-                Action::SendFile(filename) => {
-                    if let Some(body) = response::send_file(response, filename).map(|vec| vec.into()) {
-                        Body::Some(body)
-                    } else {
-                        Body::Empty
-                    }
-                }
-                Action::Stream(closure) => {
-                    response::set_streaming(response);
-                    Body::Streaming(closure)
-                }
-            }
+/// If the result is Ok, processes the action returned by the handler.
+/// Otherwise, if the result is Err, processes the action returned by the error handlers.
+fn process_handle_result(response: &mut Response, result: Result, templates: &Templates, error_handlers: &ErrorHandlers) -> Body {
+    let action = match result {
+        Ok(action) => action,
+        Err(error) => {
+            log_error(&error);
+            error_handlers.handle(&error, response)
         }
+    };
+
+    match process_action(response, action, templates) {
+        Ok(body) => body,
         Err(error) => {
-            match error.message {
-                None => {
-                    response.status(error.status);
-                    Body::Empty
+            // typically a template could not be rendered
+            log_error(&error);
+            *response = Response::new();
+            let action = error_handlers.handle(&error, response);
+            process_action(response, action, templates).unwrap_or_else(|error| {
+                error!("could not process action of error handler: {}", error);
+                *response = Response::new();
+                response.status(Status::InternalServerError);
+                Body::Empty
+            })
+        }
+    }
+}
+
+/// Logs the given error along with its chain of causes.
+///
+/// Causes are never sent to the client, so this is the only place they show up.
+fn log_error(error: &Error) {
+    use std::error::Error as StdError;
+
+    let mut message = format!("{}: {}", error.status, error);
+    let mut cause = error.cause();
+    while let Some(err) = cause {
+        message.push_str(&format!("\n  caused by: {}", err));
+        cause = err.cause();
+    }
+
+    if error.status.is_server_error() {
+        error!("{}", message);
+    } else {
+        info!("{}", message);
+    }
+}
+
+/// Calls end/send/render/redirect depending on the type of action.
+///
+/// Returns an error if the action fails, for instance if a template cannot be rendered.
+fn process_action(response: &mut Response, action: Action, templates: &Templates) -> result::Result<Body, Error> {
+    Ok(match action {
+        Action::End(status) => {
+            if let Some(status) = status {
+                response.status(status);
+            }
+            Body::Empty
+        }
+        Action::Redirect(status, url) => {
+            response.status(status);
+            response.location(url);
+            Body::Empty
+        }
+        Action::Render(name, json) => {
+            let buffer = try!(render(response, templates, &name, &json));
+            Body::Some(buffer)
+        }
+        Action::Send(body) => {
+            Body::Some(body.into())
+        }
+        Action::Json(body) => {
+            if !response.headers.has::<ContentType>() {
+                response.content_type("application/json; charset=utf-8");
+            }
+            Body::Some(body.into())
+        }
+        Action::Jsonp(callback, json) => {
+            response.content_type("application/javascript; charset=utf-8");
+
+            // the comment prevents a Rosetta Flash attack
+            let mut body = format!("/**/ typeof {0} === 'function' && {0}(", callback).into_bytes();
+            body.extend_from_slice(&json);
+            body.extend_from_slice(b");");
+            Body::Some(body.into())
+        }
+        Action::SendFile(filename) => {
+            if let Some(body) = response::send_file(response, filename).map(|vec| vec.into()) {
+                Body::Some(body)
+            } else {
+                Body::Empty
+            }
+        }
+        Action::Markdown(filename) => {
+            let mut text = String::new();
+            match File::open(&filename).and_then(|mut file| file.read_to_string(&mut text)) {
+                Ok(_) => (),
+                Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                    return Err(Error::new(Status::NotFound, Some(Cow::Borrowed("Not Found"))));
                 }
-                Some(message) => {
-                    response.status(error.status);
-                    response.content_type("text/plain");
-                    Body::Some((&*message).as_bytes().to_vec().into())
+                Err(err) => return Err(err.into())
+            }
+
+            let html = markdown::render_html(&text, templates.markdown_options());
+            let page = match layout(response, templates) {
+                Some(layout) => {
+                    // blocks left by a previous render on this thread must not leak into the layout
+                    helpers::clear_blocks();
+                    try!(templates.render_layout(layout, &json::Value::Object(BTreeMap::new()), html))
                 }
+                None => html
+            };
+            if !response.headers.has::<ContentType>() {
+                response.header(ContentType::html());
             }
+            Body::Some(page.into_bytes().into())
         }
-    }
+        Action::SendEmbedded(file) => {
+            Body::Some(response::send_embedded(response, &file).into())
+        }
+        Action::Stream(closure) => {
+            response::set_streaming(response);
+            Body::Streaming(closure)
+        }
+        Action::EventStream(closure) => {
+            response.content_type("text/event-stream");
+            response.header(CacheControl(vec![CacheDirective::NoCache]));
+            response::set_streaming(response);
+            response::set_heartbeat(response, Duration::from_secs(sse::HEARTBEAT_INTERVAL));
+            Body::Streaming(closure)
+        }
+    })
 }
 
 /// Renders the template with the given name using the given data.
 ///
 /// If no Content-Type header is set, the content type is set to `text/html`.
-fn render(response: &mut Response, handlebars: &Handlebars, name: &str, json: &json::Value) -> Buffer {
+fn render(response: &mut Response, templates: &Templates, name: &str, json: &json::Value) -> result::Result<Buffer, Error> {
+    let html = try!(templates.render_with_layout(name, json, layout(response, templates)));
     if !response.headers.has::<ContentType>() {
         response.header(ContentType::html());
     }
 
-    let result = handlebars.render(name, json);
-    result.unwrap().into_bytes().into()
+    Ok(html.into_bytes().into())
+}
+
+/// Returns the layout that applies to the given response (if any).
+fn layout<'a>(response: &'a Response, templates: &'a Templates) -> Option<&'a str> {
+    match *response::get_layout(response) {
+        Layout::Inherit => templates.default_layout(),
+        Layout::Named(ref layout) => Some(layout.as_str()),
+        Layout::Disabled => None
+    }
+}
+
+/// Sets the given timeout (if any) on the given Next.
+fn with_timeout(next: Next, timeout: Option<Duration>) -> Next {
+    match timeout {
+        Some(timeout) => next.timeout(timeout),
+        None => next
+    }
+}
+
+/// Returns `true` if the given If-None-Match header matches the given entity tag.
+///
+/// If-None-Match uses the weak comparison function, see RFC 7232 section 3.2.
+fn matches_etag(if_none_match: &IfNoneMatch, etag: &EntityTag) -> bool {
+    match *if_none_match {
+        IfNoneMatch::Any => true,
+        IfNoneMatch::Items(ref tags) => tags.iter().any(|tag| tag.weak_eq(etag))
+    }
 }
 
 /// Implements Handler for our EdgeHandler.
-impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
+impl<'scope, T: Transport + Any> Handler<T> for EdgeHandler<'scope> {
     fn on_request(&mut self, req: HttpRequest) -> Next {
         debug!("on_request");
 
-        match request::new(&self.base_url, req) {
+        server::request_started(self.handle);
+        self.started = true;
+        self.peer = server::peer();
+        if let Some(ref peer) = self.peer {
+            peer.request_started();
+        }
+        if self.endpoint.access_log.is_some() {
+            self.access = Some(Entry::new(&req, self.peer.as_ref().and_then(|peer| peer.addr())));
+        }
+
+        match request::new(&self.endpoint.base_url, req) {
             Ok(req) => {
                 let result = check_request(&req, &mut self.buffer);
                 self.is_head_request = *req.method() == Head;
+                self.is_safe_request = *req.method() == Get || *req.method() == Head;
+                self.if_none_match = req.headers().get::<IfNoneMatch>().cloned();
                 self.request = Some(req);
 
                 match result {
                     Err(msg) => self.bad_request(msg),
                     Ok(false) => self.callback(),
-                    Ok(true) => Next::read()
+                    Ok(true) => {
+                        self.reading_body = true;
+                        with_timeout(Next::read(), self.endpoint.timeouts.body_read)
+                    }
                 }
             }
             Err(error) => {
@@ -266,7 +661,7 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
         }
     }
 
-    fn on_request_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
+    fn on_request_readable(&mut self, transport: &mut Decoder<T>) -> Next {
         debug!("on_request_readable");
 
         // we can only get here if self.buffer = Some(...), or there is a bug
@@ -274,12 +669,13 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
             let body = self.buffer.as_mut().unwrap();
             if let Ok(keep_reading) = body.read_from(transport) {
                 if keep_reading {
-                    return Next::read();
+                    return with_timeout(Next::read(), self.endpoint.timeouts.body_read);
                 }
             }
         }
 
         // move body to the request
+        self.reading_body = false;
         request::set_body(self.request.as_mut(), self.buffer.take());
         self.callback()
     }
@@ -288,15 +684,55 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
         debug!("on_response");
 
         // we got here from callback directly or Resp notified the Control
-        match self.stealer.steal() {
+        self.waiting_handler = false;
+        let reply = match self.timeout_reply.take() {
+            Some((response, body)) => Steal::Data(Reply::Initial(response, body)),
+            None => self.stealer.steal()
+        };
+
+        match reply {
             Steal::Data(Reply::Initial(response, body)) => {
                 self.streaming = response::is_streaming(&response);
-                let status = response.status;
+                self.heartbeat = response::get_heartbeat(&response);
+                let mut status = response.status;
+
+                // a response whose ETag matches one given by the client is sent as 304 Not Modified
+                // for GET and HEAD, and 412 Precondition Failed otherwise (RFC 7232 section 3.2)
+                let mut precondition_failed = false;
+                if status.is_success() && !self.streaming {
+                    if let Some(ref if_none_match) = self.if_none_match {
+                        if let Some(&ETag(ref etag)) = response.headers.get::<ETag>() {
+                            if matches_etag(if_none_match, etag) {
+                                if self.is_safe_request {
+                                    status = Status::NotModified;
+                                } else {
+                                    status = Status::PreconditionFailed;
+                                    precondition_failed = true;
+                                }
+                            }
+                        }
+                    }
+                }
 
                 // set status and headers
                 res.set_status(status);
+                if let Some(ref mut access) = self.access {
+                    access.set_status(status);
+                }
                 *res.headers_mut() = response.headers;
 
+                // do not keep connections alive during shutdown
+                if self.handle.is_shutting_down() {
+                    res.headers_mut().set(Connection::close());
+                }
+
+                // nor after the last request allowed on this connection
+                if let (Some(max), Some(ref peer)) = (self.endpoint.max_requests, self.peer.as_ref()) {
+                    if peer.requests() >= max {
+                        res.headers_mut().set(Connection::close());
+                    }
+                }
+
                 // 3.3.2 Content-Length
                 // http://httpwg.org/specs/rfc7230.html#header.content-length
                 //
@@ -307,7 +743,7 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
                 // A server MAY send a Content-Length header field in a 304 (Not Modified) response
                 if status.is_informational() ||
                     status == Status::NoContent || status == Status::NotModified ||
-                    self.is_head_request {
+                    precondition_failed || self.is_head_request {
                     // we remove any ContentLength header in those cases
                     // even in 304 and response to HEAD
                     // because we cannot guarantee that the length is the same
@@ -319,7 +755,7 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
                     None => {
                         if self.streaming {
                             debug!("streaming mode, waiting");
-                            Next::wait()
+                            with_timeout(Next::wait(), self.heartbeat)
                         } else {
                             debug!("has no body, ending");
                             Next::end()
@@ -327,8 +763,11 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
                     }
                     Some(body) => {
                         debug!("has body");
+                        if let Some(ref mut access) = self.access {
+                            access.add_bytes(body.len());
+                        }
                         self.buffer = Some(body);
-                        Next::write()
+                        with_timeout(Next::write(), self.endpoint.timeouts.write)
                     }
                 }
             }
@@ -339,21 +778,30 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
         }
     }
 
-    fn on_response_writable(&mut self, transport: &mut Encoder<HttpStream>) -> Next {
+    fn on_response_writable(&mut self, transport: &mut Encoder<T>) -> Next {
         debug!("on_response_writable");
 
         loop {
             self.buffer = match self.buffer {
                 None => {
                     match self.stealer.steal() {
-                        Steal::Data(Reply::Buffer(body)) => Some(body),
+                        Steal::Data(Reply::Buffer(body)) => {
+                            if let Some(ref mut access) = self.access {
+                                access.add_bytes(body.len());
+                            }
+                            Some(body)
+                        }
                         Steal::Data(Reply::End) => {
                             debug!("done writing");
                             return Next::end();
                         }
+                        Steal::Data(Reply::Abort) => {
+                            error!("stream aborted");
+                            return Next::remove();
+                        }
                         Steal::Empty => {
                             // no data yet, wait for notification
-                            return Next::wait();
+                            return with_timeout(Next::wait(), self.heartbeat);
                         }
                         _ => panic!("unexpected")
                     }
@@ -361,7 +809,7 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
                 Some(ref mut buffer) => {
                     if let Ok(keep_writing) = buffer.write_to(transport) {
                         if keep_writing {
-                            return Next::write();
+                            return with_timeout(Next::write(), self.endpoint.timeouts.write);
                         } else {
                             // this buffer has been fully written to the transport
                             if self.streaming {
@@ -380,14 +828,143 @@ impl<'handler, 'scope> Handler<HttpStream> for EdgeHandler<'handler, 'scope> {
 
     fn on_error(&mut self, err: HyperError) -> Next {
         debug!("on_error {:?}", err);
-        Next::remove()
+
+        match err {
+            HyperError::Timeout if self.waiting_handler => {
+                // the handler did not respond in time, its response will be discarded
+                warn!("handler timed out");
+                self.waiting_handler = false;
+                self.closed.store(true, Ordering::SeqCst);
+                let error = Error::new(Status::ServiceUnavailable, Some(Cow::Borrowed("Service Unavailable")));
+                self.timeout_reply = Some(self.error_reply(Response::new(), error));
+                Next::write()
+            }
+            HyperError::Timeout if self.reading_body => {
+                // the client is too slow sending its request body
+                info!("timed out reading request body");
+                self.reading_body = false;
+                let error = Error::new(Status::RequestTimeout, Some(Cow::Borrowed("Request Timeout"))).header(Connection::close());
+                self.send_error(Response::new(), error)
+            }
+            HyperError::Timeout if self.heartbeat.is_some() && self.buffer.is_none() => {
+                // nothing was streamed for a while, a comment keeps the connection alive
+                let heartbeat = b":\n\n".to_vec();
+                if let Some(ref mut access) = self.access {
+                    access.add_bytes(heartbeat.len());
+                }
+                self.buffer = Some(heartbeat.into());
+                with_timeout(Next::write(), self.endpoint.timeouts.write)
+            }
+            HyperError::Timeout => {
+                info!("timed out writing response");
+                Next::remove()
+            }
+            _ => Next::remove()
+        }
     }
 
-    fn on_remove(self, _transport: HttpStream) {
+    fn on_remove(mut self, transport: T) {
         debug!("on_remove");
+
+        // lets a streaming closure know that the client is gone
+        self.closed.store(true, Ordering::SeqCst);
+
+        if let Some(upgrade) = self.upgrade.take() {
+            match into_tcp_stream(transport) {
+                Some(stream) => {
+                    // the handshake response is written by the WebSocket job
+                    if let Some(ref mut access) = self.access {
+                        access.set_status(Status::SwitchingProtocols);
+                    }
+                    self.pool.spawn(move || run_websocket(upgrade, stream))
+                }
+                None => {
+                    error!("cannot take over the connection for a WebSocket");
+                    server::job_started(&upgrade.handle);
+                    server::websocket_closed(&upgrade.handle);
+                }
+            }
+        }
+    }
+}
+
+impl<'scope> Drop for EdgeHandler<'scope> {
+    fn drop(&mut self) {
+        if self.started {
+            server::request_finished(self.handle);
+        }
+
+        if let Some(ref peer) = self.peer {
+            peer.request_finished();
+        }
+
+        if let (Some(access_log), Some(mut access)) = (self.endpoint.access_log.as_ref(), self.access.take()) {
+            // no response was sent: the client failed to send its request body, or the reply failed
+            if access.status().is_none() {
+                access.set_status(if self.reading_body { Status::BadRequest } else { Status::InternalServerError });
+            }
+            access_log.write(access);
+        }
+    }
+}
+
+/// Completes the WebSocket handshake and calls the WebSocket callback.
+///
+/// The connection is registered so that it is closed if still open when the shutdown timeout expires.
+/// It occupies the current pool thread until it is closed, see `server::websocket_queued`.
+fn run_websocket(upgrade: PendingUpgrade, stream: TcpStream) {
+    server::job_started(&upgrade.handle);
+    let id = server::register_socket(&upgrade.handle, &stream);
+    handle_websocket(&upgrade, stream);
+    if let Some(id) = id {
+        server::unregister_socket(&upgrade.handle, id);
+    }
+    server::websocket_closed(&upgrade.handle);
+}
+
+fn handle_websocket(upgrade: &PendingUpgrade, stream: TcpStream) {
+    let mut ws = match websocket::accept(stream, &upgrade.accept, upgrade.timeout) {
+        Ok(ws) => ws,
+        Err(e) => {
+            error!("could not complete WebSocket handshake: {}", e);
+            return;
+        }
+    };
+
+    let mut boxed_app = upgrade.routers[upgrade.index].new_instance();
+    if let Callback::WebSocket(ref f) = *upgrade.callback {
+        match catch_panic(|| f(boxed_app.as_mut(), &upgrade.request, &mut ws)) {
+            Some(Err(e)) => error!("WebSocket error: {}", e),
+            Some(Ok(())) => (),
+            None => {
+                let _ = ws.close(1011, "internal error");
+            }
+        }
+    }
+
+    if !ws.is_closed() {
+        let _ = ws.close(1000, "");
     }
 }
 
+/// Returns the TCP stream of the given transport, if it is a plain HTTP stream.
+fn into_tcp_stream<T: Any>(transport: T) -> Option<TcpStream> {
+    let transport: Box<Any> = Box::new(transport);
+    transport.downcast::<PeerStream<HttpStream>>().ok().map(|stream| http_into_tcp_stream(stream.into_inner()))
+}
+
+#[cfg(unix)]
+fn http_into_tcp_stream(transport: HttpStream) -> TcpStream {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    unsafe { TcpStream::from_raw_fd(transport.0.into_raw_fd()) }
+}
+
+#[cfg(windows)]
+fn http_into_tcp_stream(transport: HttpStream) -> TcpStream {
+    use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+    unsafe { TcpStream::from_raw_socket(transport.0.into_raw_socket()) }
+}
+
 fn check_request(req: &Request, buffer: &mut Option<Buffer>) -> ::std::result::Result<bool, &'static str> {
     let headers = req.headers();
     let http1x = { let version = req.version(); *version == Http09 || *version == Http10 || *version == Http11 };
@@ -0,0 +1,223 @@
+//! Built-in Handlebars helpers.
+//!
+//! - `markdown`: renders the given Markdown text as HTML, with the options set by `Edge::set_markdown_options`
+//! - `json`: encodes the given value as JSON, safe for inclusion in a `<script>` element
+//! - `date`: formats a date given as a Unix timestamp or an RFC 3339 string, e.g. `{{date created "%Y-%m-%d"}}`
+//! - `eq`, `ne`, `lt`, `le`, `gt`, `ge`: compare two values
+//! - `default`: writes the first value, or the second one if the first is null, false or empty
+//! - `content`: fills the block with the given name, to be shown by a layout
+//! - `block`: in a layout, shows the block with the given name, or its own content if the block is not filled
+//!
+//! Comparison helpers can be used as blocks, as in `{{#eq a b}}same{{else}}different{{/eq}}`,
+//! or as subexpressions, as in `{{#if (gt count 1)}}`, in which case they write `true` or nothing.
+
+use chrono::{DateTime, NaiveDateTime, UTC};
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, Renderable};
+
+use markdown::{self, MarkdownOptions};
+
+use serde_json::value::Value;
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::result;
+
+type HelperResult = result::Result<(), RenderError>;
+
+// blocks filled by the template being rendered on this thread
+thread_local!(static BLOCKS: RefCell<BTreeMap<String, String>> = RefCell::new(BTreeMap::new()));
+
+/// Removes the blocks filled on this thread.
+pub fn clear_blocks() {
+    BLOCKS.with(|blocks| blocks.borrow_mut().clear());
+}
+
+/// Returns the blocks filled on this thread.
+pub fn blocks() -> BTreeMap<String, String> {
+    BLOCKS.with(|blocks| blocks.borrow().clone())
+}
+
+/// Registers the built-in helpers.
+pub fn register(handlebars: &mut Handlebars) {
+    handlebars.register_helper("markdown", markdown_helper(MarkdownOptions::default()));
+    handlebars.register_helper("json", Box::new(json_helper));
+    handlebars.register_helper("date", Box::new(date_helper));
+    handlebars.register_helper("default", Box::new(default_helper));
+    handlebars.register_helper("content", Box::new(content_helper));
+    handlebars.register_helper("block", Box::new(block_helper));
+
+    handlebars.register_helper("eq", Box::new(|c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext|
+        compare_helper(c, h, r, rc, |ord| ord == Ordering::Equal)));
+    handlebars.register_helper("ne", Box::new(|c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext|
+        compare_helper(c, h, r, rc, |ord| ord != Ordering::Equal)));
+    handlebars.register_helper("lt", Box::new(|c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext|
+        compare_helper(c, h, r, rc, |ord| ord == Ordering::Less)));
+    handlebars.register_helper("le", Box::new(|c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext|
+        compare_helper(c, h, r, rc, |ord| ord != Ordering::Greater)));
+    handlebars.register_helper("gt", Box::new(|c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext|
+        compare_helper(c, h, r, rc, |ord| ord == Ordering::Greater)));
+    handlebars.register_helper("ge", Box::new(|c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext|
+        compare_helper(c, h, r, rc, |ord| ord != Ordering::Less)));
+}
+
+/// Returns the value of the parameter at the given index, or an error naming the helper.
+fn param<'a>(h: &'a Helper, index: usize) -> result::Result<&'a Value, RenderError> {
+    h.param(index).map(|param| param.value()).ok_or_else(||
+        RenderError::new(format!("Param {} not found for helper \"{}\"", index, h.name())))
+}
+
+/// Returns the `markdown` helper, which renders Markdown with the given options.
+///
+/// this code is based on code Copyright (c) 2015 Wayne Nilsen
+/// see https://github.com/waynenilsen/handlebars-markdown-helper/blob/master/src/lib.rs#L31
+///
+/// because the handlebars-markdown-helper crate does not allow custom options for Markdown rendering yet
+pub fn markdown_helper(options: MarkdownOptions) -> Box<HelperDef + 'static> {
+    Box::new(move |_: &Context, h: &Helper, _: &Handlebars, rc: &mut RenderContext| -> HelperResult {
+        let markdown_text_var = try!(h.param(0).ok_or_else(|| RenderError::new(
+            "Param not found for helper \"markdown\"")
+        ));
+        let text = try!(markdown_text_var.value().as_string().ok_or_else(||
+            RenderError::new(format!("Expected a string for parameter {:?}", markdown_text_var))
+        ));
+        let html = markdown::render_html(text, &options);
+        try!(rc.writer.write_all(html.as_bytes()));
+        Ok(())
+    })
+}
+
+fn json_helper(_: &Context, h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> HelperResult {
+    let value = try!(param(h, 0));
+
+    // prevents the value from closing the script element it is included in
+    let json = value.to_string().replace('<', "\\u003c").replace('>', "\\u003e").replace('&', "\\u0026");
+    try!(rc.writer.write_all(json.as_bytes()));
+    Ok(())
+}
+
+/// Returns the date of the given Unix timestamp, or an error if it is out of range.
+fn from_timestamp(timestamp: i64) -> result::Result<DateTime<UTC>, RenderError> {
+    NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|date| DateTime::<UTC>::from_utc(date, UTC))
+        .ok_or_else(|| RenderError::new(format!("Timestamp {} out of range for helper \"date\"", timestamp)))
+}
+
+fn date_helper(_: &Context, h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> HelperResult {
+    let value = try!(param(h, 0));
+    let format = match h.param(1) {
+        Some(param) => try!(param.value().as_string().ok_or_else(||
+            RenderError::new("Expected a string for the format of helper \"date\""))),
+        None => "%Y-%m-%d %H:%M:%S"
+    };
+
+    let date = match *value {
+        Value::I64(timestamp) => try!(from_timestamp(timestamp)),
+        Value::U64(timestamp) if timestamp <= ::std::i64::MAX as u64 => try!(from_timestamp(timestamp as i64)),
+        Value::String(ref date) => {
+            try!(DateTime::parse_from_rfc3339(date).map(|date| date.with_timezone(&UTC)).map_err(|e|
+                RenderError::new(format!("Invalid date {:?} for helper \"date\": {}", date, e))))
+        }
+        _ => return Err(RenderError::new(format!("Expected a timestamp or a date for helper \"date\", got {:?}", value)))
+    };
+
+    try!(rc.writer.write_all(date.format(format).to_string().as_bytes()));
+    Ok(())
+}
+
+/// Returns `true` if the given value is null, false, or empty.
+fn is_empty(value: &Value) -> bool {
+    match *value {
+        Value::Null | Value::Bool(false) => true,
+        Value::String(ref s) => s.is_empty(),
+        Value::Array(ref array) => array.is_empty(),
+        Value::Object(ref object) => object.is_empty(),
+        _ => false
+    }
+}
+
+fn default_helper(_: &Context, h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> HelperResult {
+    let null = Value::Null;
+    let value = h.param(0).map_or(&null, |param| param.value());
+    let value = if is_empty(value) { try!(param(h, 1)) } else { value };
+
+    let text = match *value {
+        Value::String(ref s) => s.clone(),
+        ref value => value.to_string()
+    };
+    try!(rc.writer.write_all(text.as_bytes()));
+    Ok(())
+}
+
+/// Returns the name given as first parameter of a block helper.
+fn block_name(h: &Helper) -> result::Result<String, RenderError> {
+    let name = try!(param(h, 0));
+    name.as_string().map(|name| name.to_string()).ok_or_else(||
+        RenderError::new(format!("Expected a string for the block name of helper \"{}\"", h.name())))
+}
+
+fn content_helper(c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext) -> HelperResult {
+    let name = try!(block_name(h));
+    if let Some(template) = h.template() {
+        let mut buffer = Vec::new();
+        {
+            let mut block_rc = rc.with_writer(&mut buffer);
+            try!(template.render(c, r, &mut block_rc));
+        }
+
+        let block = String::from_utf8_lossy(&buffer).into_owned();
+        BLOCKS.with(|blocks| blocks.borrow_mut().insert(name, block));
+    }
+    Ok(())
+}
+
+fn block_helper(c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext) -> HelperResult {
+    let name = try!(block_name(h));
+    match BLOCKS.with(|blocks| blocks.borrow().get(&name).cloned()) {
+        Some(block) => {
+            try!(rc.writer.write_all(block.as_bytes()));
+            Ok(())
+        }
+        None => h.template().map_or(Ok(()), |template| template.render(c, r, rc))
+    }
+}
+
+/// Compares two values: numbers are compared numerically, strings lexicographically,
+/// and other values can only be equal or not.
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(left), Some(right)) => left.partial_cmp(&right),
+        _ => match (left, right) {
+            (&Value::String(ref left), &Value::String(ref right)) => Some(left.cmp(right)),
+            _ => if left == right { Some(Ordering::Equal) } else { None }
+        }
+    }
+}
+
+fn compare_helper<F>(c: &Context, h: &Helper, r: &Handlebars, rc: &mut RenderContext, test: F) -> HelperResult
+    where F: Fn(Ordering) -> bool {
+    let left = try!(param(h, 0));
+    let right = try!(param(h, 1));
+
+    // values that cannot be ordered are only different
+    let result = match compare(left, right) {
+        Some(ordering) => test(ordering),
+        None => h.name() == "ne"
+    };
+
+    if let Some(template) = h.template() {
+        if result {
+            template.render(c, r, rc)
+        } else if let Some(inverse) = h.inverse() {
+            inverse.render(c, r, rc)
+        } else {
+            Ok(())
+        }
+    } else {
+        if result {
+            try!(rc.writer.write_all(b"true"));
+        }
+        Ok(())
+    }
+}
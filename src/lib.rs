@@ -128,7 +128,7 @@
 //!     let mut router = Router::new();
 //!     router.get("/:page", Templating::page_handler);
 //!     edge.mount("/", router);
-//!     edge.register_template("tmpl");
+//!     edge.register_template("tmpl").unwrap();
 //!     edge.start().unwrap();
 //! }
 //! ```
@@ -142,64 +142,122 @@
 //! see the example for asynchronous handling above.
 //! ```
 
+extern crate chrono;
 extern crate crossbeam;
-extern crate handlebars;
 extern crate hyper;
+extern crate libc;
+extern crate mio;
 extern crate num_cpus;
+#[cfg(feature = "tls")]
+extern crate openssl;
 extern crate pulldown_cmark;
+extern crate rustc_serialize;
 extern crate scoped_pool;
 extern crate serde;
+extern crate sha1;
 extern crate url;
+extern crate vecio;
 
 #[macro_use]
 extern crate log;
+pub extern crate handlebars;
 pub extern crate serde_json;
 
 pub use hyper::header as header;
+pub use hyper::mime::Mime;
 pub use header::CookiePair as Cookie;
 pub use hyper::status::StatusCode as Status;
 
 /// serde_json crate
 pub use serde_json as json;
 
-use handlebars::{Context, Handlebars, Helper, RenderContext, RenderError};
+use handlebars::{Handlebars, HelperDef};
 
-use hyper::net::HttpListener;
-use hyper::server::Server;
-
-use pulldown_cmark::Parser;
-use pulldown_cmark::{Options, OPTION_ENABLE_TABLES, OPTION_ENABLE_FOOTNOTES};
-use pulldown_cmark::html;
+use hyper::net::{Accept, HttpListener, Transport};
+#[cfg(feature = "tls")]
+use hyper::net::HttpsListener;
+use hyper::server::{Listening, Server};
 
 use scoped_pool::Pool;
 
 use url::Url;
 
-use std::fs::read_dir;
-use std::io::Result as IoResult;
-use std::net::ToSocketAddrs;
+use serde_json::value::ToJson;
+
+use std::any::Any;
+use std::io::{self, Result as IoResult};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::result;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
+mod access_log;
+mod assets;
 mod buffer;
 mod client;
+mod errors;
 mod handler;
+mod helpers;
+mod markdown;
+mod negotiate;
 mod router;
 mod request;
 mod response;
+mod server;
+mod sse;
+mod templates;
+#[cfg(unix)]
+mod unix;
+#[cfg(feature = "tls")]
+mod tls;
+mod websocket;
 
+pub use access_log::{AccessLog, LogFormat};
+pub use assets::{Assets, EmbeddedFile};
 pub use client::Client;
+pub use errors::{ErrorHandler, error_template, problem_json};
+pub use markdown::MarkdownOptions;
+pub use negotiate::{Negotiation, negotiate};
 pub use request::Request;
-pub use response::{Response, Result, Action, stream};
+pub use response::{Response, Result, Action, Error, stream, send_json, send_json_pretty, send_jsonp};
 pub use router::{Router};
+pub use server::{ServerHandle, Timeouts};
+pub use sse::{EventStream, event_stream};
+pub use templates::{EngineError, TemplateEngine, TemplateLoadError};
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+pub use websocket::{Message, WebSocket};
 
 /// Structure for an Edge application.
 pub struct Edge {
     base_url: Url,
-    routers: Vec<router::RouterAny>,
-    handlebars: Handlebars
+    routers: Arc<Vec<router::RouterAny>>,
+    views: PathBuf,
+    partials: Option<PathBuf>,
+    templates: Arc<templates::Templates>,
+    error_handlers: Arc<errors::ErrorHandlers>,
+    handle: ServerHandle,
+    listener: Option<HttpListener>,
+    local_addr: Option<SocketAddr>,
+    listeners: Vec<server::Listener>,
+    redirect_to_https: bool,
+    timeouts: Timeouts,
+    max_requests: Option<usize>,
+    threads: usize,
+    pool_size: usize,
+    shutdown_timeout: Duration,
+    handle_signals: bool,
+    access_log: Option<Arc<AccessLog>>
 }
 
+/// Name of the listener of the address given to `Edge::new`.
+const MAIN_LISTENER: &'static str = "main";
+
 /// ok!() means Ok(Action::End).
 /// ok!(expr) returns Ok(From::from(expr))
 #[macro_export]
@@ -220,12 +278,27 @@ impl Edge {
     /// Creates an Edge application using the given address and application.
     pub fn new(addr: &str) -> Edge {
         let mut handlebars = Handlebars::new();
-        init_handlebars(&mut handlebars).unwrap();
+        init_handlebars(&mut handlebars);
 
         Edge {
             base_url: Url::parse(&("http://".to_string() + addr)).unwrap(),
-            routers: Vec::new(),
-            handlebars: handlebars
+            routers: Arc::new(Vec::new()),
+            views: PathBuf::from("views"),
+            partials: None,
+            templates: Arc::new(templates::Templates::new(handlebars)),
+            error_handlers: Arc::new(errors::ErrorHandlers::new()),
+            handle: server::new(),
+            listener: None,
+            local_addr: None,
+            listeners: Vec::new(),
+            redirect_to_https: false,
+            timeouts: Timeouts::default(),
+            max_requests: None,
+            threads: num_cpus::get(),
+            pool_size: num_cpus::get() * 4,
+            shutdown_timeout: Duration::from_secs(30),
+            handle_signals: false,
+            access_log: None
         }
     }
 
@@ -235,96 +308,445 @@ impl Edge {
     pub fn mount<T>(&mut self, mount: &str, router: Router<T>) {
         let mut router = router::get_inner(router);
         router.set_prefix(mount);
-        self.routers.push(router)
+        shared_mut(&mut self.routers).push(router)
+    }
+
+    /// Mounts the given router at the given path, only reachable on the listeners with the given names.
+    ///
+    /// The listener of the address given to `new` is named `main`, the one created by `https` is named `https`,
+    /// and the others are named when created by `listen`.
+    pub fn mount_on<T>(&mut self, listeners: &[&str], mount: &str, router: Router<T>) {
+        let mut router = router::get_inner(router);
+        router.set_prefix(mount);
+        router.set_listeners(listeners.iter().map(|name| name.to_string()).collect());
+        shared_mut(&mut self.routers).push(router)
+    }
+
+    /// Sets the directory where templates are located, `views` by default.
+    ///
+    /// Partials found in the `partials` subdirectory are registered, replacing those
+    /// of the previous directory. Otherwise, partials are registered by `start`.
+    pub fn set_views<P: AsRef<Path>>(&mut self, views: P) -> result::Result<(), TemplateLoadError> {
+        if let Some(partials) = self.partials.take() {
+            self.templates.handlebars().unregister_dir(&partials);
+        }
+
+        self.views = views.as_ref().to_path_buf();
+        self.load_partials()
+    }
+
+    /// Registers the partials found in the `partials` subdirectory of the views directory (if it exists).
+    fn load_partials(&mut self) -> result::Result<(), TemplateLoadError> {
+        let partials = self.views.join("partials");
+        if partials.exists() {
+            try!(self.templates.handlebars().register_flat(&partials));
+        }
+
+        self.partials = Some(partials);
+        Ok(())
     }
 
-    // Registers a template with the given name.
-    pub fn register_template(&mut self, name: &str) {
-        let mut path = PathBuf::new();
-        path.push("views");
+    /// Registers a template with the given name.
+    ///
+    /// The template is read from `<views>/<name>.hbs`.
+    pub fn register_template(&mut self, name: &str) -> result::Result<(), TemplateLoadError> {
+        let mut path = self.views.clone();
         path.push(name);
         path.set_extension("hbs");
 
-        self.handlebars.register_template_file(name, &path).unwrap();
+        self.templates.handlebars().register_file(name, &path)
+    }
+
+    /// Sets the layout used by default to render templates, see `Router::set_layout` and `Response::layout`.
+    ///
+    /// The layout is a template that is given the rendered template as `body`, and
+    /// shows blocks filled by the template; see the `content` and `block` helpers.
+    pub fn set_layout(&mut self, name: &str) {
+        shared_mut(&mut self.templates).set_default_layout(Some(name.to_string()));
+    }
+
+    /// Registers a template (or partial) with the given name from the given source.
+    ///
+    /// Together with `include_str!`, this allows templates to be embedded in the binary.
+    pub fn register_template_string(&mut self, name: &str, source: &str) -> result::Result<(), TemplateLoadError> {
+        self.templates.handlebars().register_string(name, source)
+    }
+
+    /// Registers all templates found in the views directory and its subdirectories.
+    ///
+    /// Each template is named after its path relative to the views directory, without
+    /// the `.hbs` extension, for example `admin/users/list`.
+    pub fn register_templates(&mut self) -> result::Result<(), TemplateLoadError> {
+        let partials = self.views.join("partials");
+        self.templates.handlebars().register_recursive(&self.views, &self.views, Some(&partials))
+    }
+
+    /// Enables or disables development mode.
+    ///
+    /// In development mode, server errors caused by another error (for example a template
+    /// that cannot be rendered) are shown on a debug page with their chain of causes,
+    /// unless an error handler is registered for them.
+    /// Template files (including partials) are also checked before each render,
+    /// and registered again if they were modified, so there is no need to restart the server.
+    ///
+    /// Never enable this mode in production, as it reveals internal details.
+    pub fn development(&mut self, enabled: bool) {
+        shared_mut(&mut self.error_handlers).set_debug(enabled);
+        self.templates.handlebars().set_reload(enabled);
+    }
+
+    /// Sets the engine used to render templates, replacing Handlebars.
+    ///
+    /// Templates and helpers registered on Edge are only used by the Handlebars engine,
+    /// and so are the `content` and `block` helpers of layouts: other engines only get `body` in layouts.
+    pub fn set_template_engine(&mut self, engine: Box<TemplateEngine>) {
+        shared_mut(&mut self.templates).set_engine(engine);
     }
 
-    /// Runs the server in one thread per cpu.
+    /// Renders the template with the given name using the given data, and returns the result.
     ///
-    /// Creates one instance of `T` per request by calling `Default::default`.
-    /// This method blocks the current thread.
+    /// Unlike `Action::Render`, this does not need a response, which is useful for tests and emails.
+    pub fn render_to_string<T: ToJson>(&self, name: &str, data: T) -> result::Result<String, EngineError> {
+        self.templates.render(name, &data.to_json())
+    }
+
+    /// Registers a Handlebars helper with the given name.
+    ///
+    /// Besides `markdown`, the following helpers are available out of the box:
+    /// `json`, `date`, `eq`, `ne`, `lt`, `le`, `gt`, `ge` and `default`.
+    /// A helper registered with the same name as a built-in one replaces it.
+    ///
+    /// Decorators cannot be registered: the version of Handlebars used by Edge does not support them.
+    pub fn register_helper(&mut self, name: &str, helper: Box<HelperDef + 'static>) {
+        self.templates.handlebars().register_helper(name, helper);
+    }
+
+    /// Sets the options used to render Markdown, by the `markdown` helper and `Action::Markdown`.
+    ///
+    /// Use `MarkdownOptions::safe()` when rendering content provided by users: raw HTML
+    /// and links with dangerous URLs such as `javascript:` are removed from the output.
+    /// A custom helper registered as `markdown` with `register_helper` is kept, and does not use these options.
+    pub fn set_markdown_options(&mut self, options: MarkdownOptions) {
+        shared_mut(&mut self.templates).set_markdown_options(options);
+    }
+
+    /// Registers a handler for errors with the given status.
+    ///
+    /// Error handlers apply to errors returned by handlers, as well as to
+    /// 404 Not Found, 405 Method Not Allowed and 400 Bad Request errors.
+    pub fn error_handler(&mut self, status: Status, handler: ErrorHandler) {
+        shared_mut(&mut self.error_handlers).insert(status, handler);
+    }
+
+    /// Registers a handler for errors that have no specific handler.
+    pub fn default_error_handler(&mut self, handler: ErrorHandler) {
+        shared_mut(&mut self.error_handlers).set_default(handler);
+    }
+
+    /// Binds the address given to `new`, and returns the address actually bound.
+    ///
+    /// This is useful with port 0, which binds an ephemeral port: the base URL is updated
+    /// with the actual port, so that the address can be known before calling `start`.
+    /// If this method is not called, `start` binds the address itself.
+    pub fn bind(&mut self) -> IoResult<SocketAddr> {
+        let listener = try!(server::bind(&mut self.base_url));
+        let local_addr = try!(server::local_addr(&listener));
+        self.listener = Some(listener);
+        self.local_addr = Some(local_addr);
+        Ok(local_addr)
+    }
+
+    /// Returns the address the server is bound to, once `bind` or `start` has been called.
+    ///
+    /// The address is kept after `start` returns.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Serves HTTPS on the given address, in addition to HTTP on the address given to `new`.
+    ///
+    /// Requests received over HTTPS are marked as secure, see `Request::is_secure`.
+    /// WebSocket routes are only available over HTTP.
+    /// This method is available with the `tls` feature.
+    /// It returns an error if the address is invalid.
+    #[cfg(feature = "tls")]
+    pub fn https(&mut self, addr: &str, config: TlsConfig) -> IoResult<()> {
+        let base_url = try!(parse_url("https", addr));
+        self.listeners.push(server::Listener { name: "https".to_string(), kind: server::Kind::Https(base_url, config) });
+        Ok(())
+    }
+
+    /// Serves HTTP on the given listening socket, already open and bound,
+    /// in addition to the address given to `new`.
+    #[cfg(unix)]
+    pub fn listen_fd(&mut self, fd: RawFd) {
+        self.listeners.push(server::Listener { name: format!("fd:{}", fd), kind: server::Kind::Fd(fd) });
+    }
+
+    /// Serves HTTP on the listening sockets passed by systemd socket activation (if any),
+    /// in addition to the address given to `new`, and returns their number.
+    #[cfg(unix)]
+    pub fn listen_systemd(&mut self) -> usize {
+        let fds = server::systemd_fds();
+        for &fd in &fds {
+            self.listen_fd(fd);
+        }
+        fds.len()
+    }
+
+    /// Serves HTTP on a Unix domain socket at the given path, in addition to the address given to `new`,
+    /// for example behind a reverse proxy on the same machine.
+    ///
+    /// A socket left at this path by a previous run is replaced. When `mode` is given, the permissions
+    /// of the socket are set to it (for example `0o660`) once it is bound.
+    /// Requests received on this listener have `http://localhost` as base URL,
+    /// and WebSocket routes are not available on it.
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(&mut self, name: &str, path: P, mode: Option<u32>) {
+        let kind = server::Kind::Unix(path.as_ref().to_path_buf(), mode);
+        self.listeners.push(server::Listener { name: name.to_string(), kind: kind });
+    }
+
+    /// Serves HTTP on the given address, in addition to the address given to `new`.
+    ///
+    /// The name of the listener can be given to `mount_on` to restrict routers to this listener,
+    /// for example to serve administration routes on an internal port only.
+    /// It returns an error if the address is invalid.
+    pub fn listen(&mut self, name: &str, addr: &str) -> IoResult<()> {
+        let base_url = try!(parse_url("http", addr));
+        self.listeners.push(server::Listener { name: name.to_string(), kind: server::Kind::Http(base_url) });
+        Ok(())
+    }
+
+    /// When enabled, requests received on the address given to `new` are redirected to the HTTPS listener, see `https`.
+    pub fn redirect_to_https(&mut self, enabled: bool) {
+        self.redirect_to_https = enabled;
+    }
+
+    /// Returns the base URL of the application, updated with the actual port once bound.
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Returns a handle that can be used to shut down the server once started.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Sets the timeouts of connections, see `Timeouts` for the defaults.
+    ///
+    /// A slow client that sends its request or reads the response too slowly is disconnected,
+    /// or sent 408 Request Timeout if its request body is incomplete.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Sets the maximum number of requests served on a connection, unlimited by default.
+    ///
+    /// The response to the last request carries `Connection: close`, so that the client opens a new connection.
+    pub fn max_requests_per_connection(&mut self, max: usize) {
+        assert!(max > 0, "at least one request per connection is needed");
+        self.max_requests = Some(max);
+    }
+
+    /// Sets the number of threads accepting connections and parsing requests,
+    /// by default the number of CPUs.
+    pub fn threads(&mut self, threads: usize) {
+        assert!(threads > 0, "at least one thread is needed");
+        self.threads = threads;
+    }
+
+    /// Sets the number of threads running handlers, by default four times the number of CPUs.
+    ///
+    /// WebSocket connections may occupy all of these threads but one, see `Router::websocket`.
+    pub fn pool_size(&mut self, pool_size: usize) {
+        assert!(pool_size > 0, "the pool needs at least one thread");
+        self.pool_size = pool_size;
+    }
+
+    /// Sets the maximum number of requests waiting for a handler thread, unlimited by default.
+    ///
+    /// Beyond this limit, requests are answered with 503 Service Unavailable and `Retry-After`.
+    pub fn max_pending_jobs(&mut self, max: usize) {
+        server::set_max_pending_jobs(&self.handle, max);
+    }
+
+    /// Sets how long requests in progress are given to complete after shutdown is requested,
+    /// 30 seconds by default. Connections still open after this timeout are closed.
+    ///
+    /// `start` returns without waiting for handlers that are still running; until they return,
+    /// the application cannot be modified (mounting routers, registering error handlers, etc.).
+    pub fn shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Enables or disables graceful shutdown on SIGTERM and SIGINT, disabled by default.
+    ///
+    /// When enabled, `start` installs process-wide signal handlers, replacing any existing ones.
+    pub fn handle_signals(&mut self, enabled: bool) {
+        self.handle_signals = enabled;
+    }
+
+    /// Records every request in the given access log, none by default.
+    ///
+    /// When signals are handled (see `handle_signals`), a log file is reopened on SIGHUP.
+    pub fn access_log(&mut self, access_log: AccessLog) {
+        self.access_log = Some(Arc::new(access_log));
+    }
+
+    /// Runs the server, see `threads` and `pool_size`.
+    ///
+    /// Creates one instance of `T` per request, see `Router::new` and `Router::with_state`.
+    /// This method blocks the current thread until the server is shut down, see `handle`.
+    /// Once it has returned, it can be called again to serve the application anew.
+    /// It returns an error if the address cannot be bound, or if partials cannot be registered.
     pub fn start(&mut self) -> IoResult<()> {
         assert!(!self.routers.is_empty(), "No router registered! Please mount at least one router");
 
+        // a previous run leaves the handle shut down
+        server::reset(&self.handle);
+        server::set_pool_size(&self.handle, self.pool_size);
+
+        // partials of the default views directory, unless set_views was called
+        if self.partials.is_none() {
+            try!(self.load_partials().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+
+        // logs panics in handlers, which are turned into 500 responses
+        handler::set_panic_hook();
+
+        if self.handle_signals {
+            server::install_signal_handlers();
+            if self.access_log.as_ref().map_or(false, |access_log| access_log.is_file()) {
+                access_log::install_signal_handler();
+            }
+        }
+
         // get address and start listening
-        let addr = self.base_url.to_socket_addrs().unwrap().next().unwrap();
-        let listener = HttpListener::bind(&addr).unwrap();
-
-        let num_cpus = num_cpus::get();
-        let pool = Pool::new(num_cpus * 4);
-        pool.scoped(|pool_scope| {
-            crossbeam::scope(|scope| {
-                for i in 0..num_cpus {
-                    let listener = listener.try_clone().unwrap();
-                    let base_url = &self.base_url;
-                    let routers = &self.routers;
-                    let handlebars = &self.handlebars;
-                    scope.spawn(move || {
-                        info!("thread {} listening on http://{}", i, addr);
-                        Server::new(listener).handle(move |control| {
-                            handler::EdgeHandler::new(pool_scope, &base_url, &routers, &handlebars, control)
-                        }).unwrap();
-                    });
-                }
+        if self.listener.is_none() {
+            try!(self.bind());
+        }
+
+        // bind additional listeners
+        let mut bound = Vec::new();
+        for listener in self.listeners.drain(..) {
+            let name = listener.name.clone();
+            let (base_url, listener) = try!(server::bind_listener(listener));
+            bound.push((name, base_url, listener));
+        }
+
+        let https_url = bound.iter().map(|&(_, ref base_url, _)| base_url).find(|base_url| base_url.scheme() == "https").cloned();
+        if self.redirect_to_https && https_url.is_none() {
+            warn!("cannot redirect to HTTPS: no HTTPS listener");
+        }
+        bound.insert(0, (MAIN_LISTENER.to_string(), self.base_url.clone(), server::Bound::Http(self.listener.take().unwrap())));
+
+        // one listener per thread for each endpoint
+        let mut endpoints = Vec::with_capacity(bound.len());
+        let mut listeners = Vec::with_capacity(bound.len() * self.threads);
+        for (index, (name, base_url, listener)) in bound.into_iter().enumerate() {
+            let websocket = listener.supports_websocket();
+            for _ in 0..self.threads {
+                listeners.push((index, try!(listener.try_clone())));
+            }
+
+            let redirect = if self.redirect_to_https && name == MAIN_LISTENER { https_url.clone() } else { None };
+            endpoints.push(server::Endpoint {
+                name: name,
+                base_url: base_url,
+                redirect: redirect,
+                timeouts: self.timeouts,
+                max_requests: self.max_requests,
+                websocket: websocket,
+                access_log: self.access_log.clone()
             });
+        }
+
+        let pool = Pool::new(self.pool_size);
+
+        // listening servers, None once they have been closed
+        let listenings = Mutex::new(Some(Vec::new()));
+        let running = AtomicUsize::new(listeners.len());
+
+        let edge = &*self;
+        let endpoints = &endpoints;
+        let pool = &pool;
+        let completed = crossbeam::scope(|scope| {
+            for (i, (index, listener)) in listeners.into_iter().enumerate() {
+                let listenings = &listenings;
+                let running = &running;
+                scope.spawn(move || {
+                    let endpoint = &endpoints[index];
+                    info!("thread {} listening on {}", i, endpoint.base_url);
+                    match listener {
+                        server::Bound::Http(listener) => serve(i, listener, edge, endpoint, pool, listenings),
+                        #[cfg(feature = "tls")]
+                        server::Bound::Https(listener, ssl) => serve(i, HttpsListener::with_listener(listener, ssl), edge, endpoint, pool, listenings),
+                        #[cfg(unix)]
+                        server::Bound::Unix(listener) => serve(i, listener, edge, endpoint, pool, listenings)
+                    }
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+
+            let completed = server::wait(&edge.handle, &running, edge.handle_signals, edge.shutdown_timeout);
+            if let Some(listenings) = listenings.lock().unwrap().take() {
+                for listening in listenings {
+                    listening.close();
+                }
+            }
+            completed
         });
 
+        if completed {
+            pool.shutdown();
+        } else {
+            // handlers still running are not waited for, their threads exit when they return
+            let pool = pool.clone();
+            thread::spawn(move || pool.shutdown());
+        }
+
+        info!("server stopped");
         Ok(())
     }
 }
 
-fn render_html(text: &str) -> String {
-    let mut opts = Options::empty();
-    opts.insert(OPTION_ENABLE_TABLES);
-    opts.insert(OPTION_ENABLE_FOOTNOTES);
-
-    let mut s = String::with_capacity(text.len() * 3 / 2);
-    let p = Parser::new_ext(text, opts);
-    html::push_html(&mut s, p);
-    s
-}
+/// Serves requests received by the given listener until it is closed.
+fn serve<'scope, A>(i: usize, listener: A, edge: &'scope Edge, endpoint: &'scope server::Endpoint, pool: &'scope Pool, listenings: &Mutex<Option<Vec<Listening>>>)
+    where A: Accept, A::Output: Transport + Any {
+    let mut server = Server::new(server::PeerListener::new(listener, endpoint.timeouts.header_read));
+    if let Some(keep_alive) = endpoint.timeouts.keep_alive {
+        server = server.idle_timeout(keep_alive);
+    }
 
-/// this code is based on code Copyright (c) 2015 Wayne Nilsen
-/// see https://github.com/waynenilsen/handlebars-markdown-helper/blob/master/src/lib.rs#L31
-///
-/// because the handlebars-markdown-helper crate does not allow custom options for Markdown rendering yet
-fn markdown_helper(_: &Context, h: &Helper, _ : &Handlebars, rc: &mut RenderContext) -> result::Result<(), RenderError> {
-    let markdown_text_var = try!(h.param(0).ok_or_else(|| RenderError::new(
-        "Param not found for helper \"markdown\"")
-    ));
-    let markdown = try!(markdown_text_var.value().as_string().ok_or_else(||
-        RenderError::new(format!("Expected a string for parameter {:?}", markdown_text_var))
-    ));
-    let html = render_html(markdown);
-    try!(rc.writer.write_all(html.as_bytes()));
-    Ok(())
-}
+    let result = server.handle(move |control| {
+        handler::EdgeHandler::new(pool, endpoint, &edge.routers, &edge.templates, &edge.error_handlers, &edge.handle, control)
+    });
 
-fn init_handlebars(handlebars: &mut Handlebars) -> IoResult<()> {
-    // register markdown helper
-    handlebars.register_helper("markdown", Box::new(::markdown_helper));
-
-    // register partials folder (if it exists)
-    let partials = Path::new("views/partials");
-    if partials.exists() {
-        for it in try!(read_dir("views/partials")) {
-            let entry = try!(it);
-            let path = entry.path();
-            if path.extension().is_some() && path.extension().unwrap() == "hbs" {
-                let name = path.file_stem().unwrap().to_str().unwrap();
-                handlebars.register_template_file(name, path.as_path()).unwrap();
+    match result {
+        Ok((listening, server_loop)) => {
+            match *listenings.lock().unwrap() {
+                Some(ref mut listenings) => listenings.push(listening),
+                None => listening.close()
             }
+            server_loop.run();
         }
+        Err(e) => error!("thread {} could not start server: {}", i, e)
     }
+}
+
+/// Parses the URL with the given scheme and address.
+fn parse_url(scheme: &str, addr: &str) -> IoResult<Url> {
+    Url::parse(&format!("{}://{}", scheme, addr)).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Returns the value shared with handler threads, which can only be modified while the server is not running.
+fn shared_mut<T>(shared: &mut Arc<T>) -> &mut T {
+    Arc::get_mut(shared).expect("cannot modify the application while handlers of a previous run are still running")
+}
 
-    Ok(())
+fn init_handlebars(handlebars: &mut Handlebars) {
+    // register built-in helpers
+    helpers::register(handlebars);
 }
+
@@ -0,0 +1,179 @@
+//! Markdown rendering, used by the `markdown` helper and `Action::Markdown`.
+//!
+//! In safe mode, raw HTML is removed from the output, and links or images whose URL
+//! uses a dangerous scheme (`javascript:`, `vbscript:`, `data:` except for images)
+//! point to `#` instead. Use safe mode when rendering content provided by users.
+
+use pulldown_cmark::{Event, Parser, Tag};
+use pulldown_cmark::{Options, OPTION_ENABLE_TABLES, OPTION_ENABLE_FOOTNOTES};
+use pulldown_cmark::html;
+
+use std::borrow::Cow;
+
+/// Options for Markdown rendering.
+#[derive(Clone, Copy, Debug)]
+pub struct MarkdownOptions {
+    /// Enables tables, enabled by default.
+    pub tables: bool,
+
+    /// Enables footnotes, enabled by default.
+    pub footnotes: bool,
+
+    /// Strips raw HTML and dangerous URLs, disabled by default.
+    pub safe: bool
+}
+
+impl Default for MarkdownOptions {
+    fn default() -> MarkdownOptions {
+        MarkdownOptions {
+            tables: true,
+            footnotes: true,
+            safe: false
+        }
+    }
+}
+
+impl MarkdownOptions {
+    /// Returns the default options with safe mode enabled.
+    pub fn safe() -> MarkdownOptions {
+        MarkdownOptions {
+            safe: true,
+            ..MarkdownOptions::default()
+        }
+    }
+}
+
+/// Renders the given Markdown text as HTML.
+pub fn render_html(text: &str, options: &MarkdownOptions) -> String {
+    let mut opts = Options::empty();
+    if options.tables {
+        opts.insert(OPTION_ENABLE_TABLES);
+    }
+    if options.footnotes {
+        opts.insert(OPTION_ENABLE_FOOTNOTES);
+    }
+
+    let mut s = String::with_capacity(text.len() * 3 / 2);
+    let p = Parser::new_ext(text, opts);
+    if options.safe {
+        html::push_html(&mut s, p.filter_map(sanitize));
+    } else {
+        html::push_html(&mut s, p);
+    }
+    s
+}
+
+/// Removes raw HTML, and replaces dangerous URLs of links and images.
+fn sanitize(event: Event) -> Option<Event> {
+    match event {
+        Event::Html(_) | Event::InlineHtml(_) => None,
+        Event::Start(tag) => Some(Event::Start(sanitize_tag(tag))),
+        Event::End(tag) => Some(Event::End(sanitize_tag(tag))),
+        event => Some(event)
+    }
+}
+
+fn sanitize_tag(tag: Tag) -> Tag {
+    match tag {
+        Tag::Link(url, title) => Tag::Link(sanitize_url(url, false), title),
+        Tag::Image(url, title) => Tag::Image(sanitize_url(url, true), title),
+        tag => tag
+    }
+}
+
+fn sanitize_url(url: Cow<str>, image: bool) -> Cow<str> {
+    if is_safe_url(&url, image) {
+        url
+    } else {
+        Cow::Borrowed("#")
+    }
+}
+
+/// Returns `true` if the scheme of the given URL (if any) cannot run scripts.
+///
+/// Browsers ignore whitespace and control characters in the scheme, and scheme names
+/// are case-insensitive, so these are removed before checking.
+fn is_safe_url(url: &str, image: bool) -> bool {
+    let normalized: String = url.chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .take(16)
+        .collect::<String>()
+        .to_lowercase();
+
+    if normalized.starts_with("javascript:") || normalized.starts_with("vbscript:") {
+        false
+    } else if normalized.starts_with("data:") {
+        image && normalized.starts_with("data:image/") && !normalized.starts_with("data:image/svg")
+    } else {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_safe_url, render_html, MarkdownOptions};
+
+    #[test]
+    fn script_schemes_are_unsafe_in_any_case() {
+        assert!(!is_safe_url("javascript:alert(1)", false));
+        assert!(!is_safe_url("JaVaScRiPt:alert(1)", false));
+        assert!(!is_safe_url("VBScript:msgbox(1)", false));
+    }
+
+    #[test]
+    fn obfuscated_schemes_are_unsafe() {
+        assert!(!is_safe_url(" javascript:alert(1)", false));
+        assert!(!is_safe_url("java\tscript:alert(1)", false));
+        assert!(!is_safe_url("java\nscript:alert(1)", false));
+        assert!(!is_safe_url("java\u{0}script:alert(1)", false));
+        assert!(!is_safe_url("\u{1}\u{1f}javascript:alert(1)", false));
+    }
+
+    #[test]
+    fn data_urls_are_only_safe_for_raster_images() {
+        assert!(is_safe_url("data:image/png;base64,AAAA", true));
+        assert!(!is_safe_url("data:image/png;base64,AAAA", false));
+        assert!(!is_safe_url("data:image/svg+xml;base64,AAAA", true));
+        assert!(!is_safe_url("DATA:IMAGE/SVG+XML,<svg/onload=alert(1)>", true));
+        assert!(!is_safe_url("data:text/html,<script>alert(1)</script>", true));
+    }
+
+    #[test]
+    fn ordinary_urls_are_safe() {
+        assert!(is_safe_url("https://example.com/", false));
+        assert!(is_safe_url("/relative/path", false));
+        assert!(is_safe_url("#anchor", false));
+        assert!(is_safe_url("mailto:someone@example.com", false));
+    }
+
+    #[test]
+    fn safe_mode_replaces_dangerous_links_and_images() {
+        let html = render_html("[x](JaVaScRiPt:alert(1)) ![y](data:image/svg+xml,z)", &MarkdownOptions::safe());
+        assert!(!html.to_lowercase().contains("javascript"));
+        assert!(!html.contains("data:image/svg"));
+        assert!(html.contains("href=\"#\""));
+        assert!(html.contains("src=\"#\""));
+    }
+
+    #[test]
+    fn safe_mode_strips_inline_html() {
+        let html = render_html("a <img src=x onerror=alert(1)> b <script>alert(1)</script>", &MarkdownOptions::safe());
+        assert!(!html.contains("<img"));
+        assert!(!html.contains("<script"));
+        assert!(html.contains("a "));
+    }
+
+    #[test]
+    fn safe_mode_strips_block_html() {
+        let html = render_html("<div onclick=\"alert(1)\">\n<script>alert(1)</script>\n</div>\n\ntext", &MarkdownOptions::safe());
+        assert!(!html.contains("<div"));
+        assert!(!html.contains("<script"));
+        assert!(html.contains("<p>text</p>"));
+    }
+
+    #[test]
+    fn unsafe_mode_keeps_html() {
+        let html = render_html("<div>x</div>", &MarkdownOptions::default());
+        assert!(html.contains("<div>x</div>"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Content negotiation based on the Accept header.
+
+use hyper::header::{Accept, ContentType};
+use hyper::mime::{Attr, Mime, TopLevel, SubLevel, Value};
+use hyper::status::StatusCode as Status;
+
+use serde_json::value as json;
+use serde_json::value::ToJson;
+
+use request::Request;
+use response::{Action, Response, Result};
+
+/// A representation offered by a handler.
+enum Representation {
+    Template(String),
+    Json
+}
+
+/// Selects how to render data depending on what the client accepts.
+///
+/// Created with `negotiate`, representations are offered in order of preference,
+/// and `respond` picks the best match:
+///
+/// ```ignore
+/// negotiate(req, data).html("users").json().respond(res)
+/// ```
+pub struct Negotiation<'a> {
+    request: &'a Request,
+    data: json::Value,
+    offers: Vec<(Mime, Representation)>
+}
+
+/// Starts negotiating the representation of the given data for the given request.
+pub fn negotiate<'a, T: ToJson>(request: &'a Request, data: T) -> Negotiation<'a> {
+    Negotiation {
+        request: request,
+        data: data.to_json(),
+        offers: Vec::new()
+    }
+}
+
+impl<'a> Negotiation<'a> {
+    /// Offers to render the template with the given name as `text/html`.
+    pub fn html(self, name: &str) -> Self {
+        self.template(Mime(TopLevel::Text, SubLevel::Html, vec![(Attr::Charset, Value::Utf8)]), name)
+    }
+
+    /// Offers to render the template with the given name as the given media type.
+    ///
+    /// A media type can be parsed from a string, for example `"text/plain".parse::<Mime>()`.
+    pub fn template(mut self, mime: Mime, name: &str) -> Self {
+        self.offers.push((mime, Representation::Template(name.to_string())));
+        self
+    }
+
+    /// Offers to send the data as `application/json`.
+    pub fn json(mut self) -> Self {
+        let mime = Mime(TopLevel::Application, SubLevel::Json, vec![(Attr::Charset, Value::Utf8)]);
+        self.offers.push((mime, Representation::Json));
+        self
+    }
+
+    /// Selects the best representation, and sets the Content-Type and Vary headers accordingly.
+    ///
+    /// Returns a 406 Not Acceptable error if the client accepts none of the representations offered.
+    pub fn respond(self, response: &mut Response) -> Result {
+        response.header_raw("Vary", "Accept");
+
+        let index = match self.request.headers().get::<Accept>() {
+            None => if self.offers.is_empty() { None } else { Some(0) },
+            Some(accept) => best_match(accept, &self.offers)
+        };
+
+        if let Some(index) = index {
+            let (mime, representation) = self.offers.into_iter().nth(index).unwrap();
+            response.header(ContentType(mime));
+            Ok(match representation {
+                Representation::Template(name) => Action::Render(name, self.data),
+                Representation::Json => Action::Json(self.data.to_string().into_bytes())
+            })
+        } else {
+            Err(Status::NotAcceptable.into())
+        }
+    }
+}
+
+/// Returns the index of the offer with the highest quality, the first one in case of a tie.
+fn best_match(accept: &Accept, offers: &[(Mime, Representation)]) -> Option<usize> {
+    let mut best = None;
+    let mut best_quality = 0;
+    for (index, &(ref offer, _)) in offers.iter().enumerate() {
+        let quality = quality(accept, offer);
+        if quality > best_quality {
+            best = Some(index);
+            best_quality = quality;
+        }
+    }
+    best
+}
+
+/// Returns the quality of the given offer, as given by the most specific matching media range.
+///
+/// See RFC 7231 section 5.3.2.
+fn quality(accept: &Accept, offer: &Mime) -> u16 {
+    let Mime(ref top, ref sub, _) = *offer;
+
+    let mut result = None;
+    for item in accept.iter() {
+        let Mime(ref range_top, ref range_sub, _) = item.item;
+        let specificity =
+            if range_top == top && range_sub == sub {
+                2
+            } else if range_top == top && *range_sub == SubLevel::Star {
+                1
+            } else if *range_top == TopLevel::Star && *range_sub == SubLevel::Star {
+                0
+            } else {
+                continue;
+            };
+
+        match result {
+            Some((current, _)) if current >= specificity => (),
+            _ => result = Some((specificity, item.quality.0))
+        }
+    }
+
+    result.map_or(0, |(_, quality)| quality)
+}
@@ -12,9 +12,10 @@ use hyper::server::Request as HttpRequest;
 
 use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::io::{Error as IoError, ErrorKind};
+use std::time::{Duration, Instant};
 
 use buffer::Buffer;
+use response::Error;
 
 use serde_json as json;
 
@@ -29,7 +30,9 @@ pub struct Request {
     path: Vec<String>,
     query: Option<BTreeMap<String, String>>,
     params: Option<BTreeMap<String, String>>,
-    body: Option<Buffer>
+    body: Option<Buffer>,
+    secure: bool,
+    deadline: Option<Instant>
 }
 
 pub fn new(base_url: &Url, inner: HttpRequest) -> Result<Request, ParseError> {
@@ -55,7 +58,9 @@ pub fn new(base_url: &Url, inner: HttpRequest) -> Result<Request, ParseError> {
         path: path,
         query: query,
         params: None,
-        body: None})
+        body: None,
+        secure: base_url.scheme() == "https",
+        deadline: None})
 }
 
 pub fn set_body(request: Option<&mut Request>, body: Option<Buffer>) {
@@ -65,11 +70,11 @@ pub fn set_body(request: Option<&mut Request>, body: Option<Buffer>) {
 }
 
 impl Request {
-    /// Returns this request's body as a vector of bytes.
-    pub fn body(&self) -> Result<&[u8], IoError> {
+    /// Returns this request's body as a vector of bytes, or a 400 Bad Request error if there is none.
+    pub fn body(&self) -> Result<&[u8], Error> {
         match self.body {
             Some(ref buffer) => Ok(buffer.as_ref()),
-            None => Err(IoError::new(ErrorKind::UnexpectedEof, "empty body"))
+            None => Err(Error::new(Status::BadRequest, Some(Cow::Borrowed("empty body"))))
         }
     }
 
@@ -84,7 +89,9 @@ impl Request {
     ///
     /// The Content-Type header must indicate ```application/x-www-form-urlencoded```.
     /// Returns a (key, value) map of clone-on-write strings.
-    pub fn form<'a>(&'a self) -> Result<BTreeMap<Cow<'a, str>, Cow<'a, str>>, IoError> {
+    ///
+    /// Errors are client errors: 400 Bad Request, or 415 Unsupported Media Type if the Content-Type is wrong.
+    pub fn form<'a>(&'a self) -> Result<BTreeMap<Cow<'a, str>, Cow<'a, str>>, Error> {
         let body = try!(self.body());
 
         match self.headers().get::<ContentType>() {
@@ -92,21 +99,23 @@ impl Request {
                 let parse = url::form_urlencoded::parse(body);
                 Ok(parse.collect())
             }
-            Some(_) => Err(IoError::new(ErrorKind::InvalidInput, "invalid Content-Type, expected application/x-www-form-urlencoded")),
-            None => Err(IoError::new(ErrorKind::InvalidInput, "missing Content-Type header"))
+            Some(_) => Err(Error::new(Status::UnsupportedMediaType, Some(Cow::Borrowed("invalid Content-Type, expected application/x-www-form-urlencoded")))),
+            None => Err(Error::new(Status::BadRequest, Some(Cow::Borrowed("missing Content-Type header"))))
         }
     }
 
     /// Parses the body of this request as JSON (indicated by ```application/json``` content type).
-    pub fn json(&self) -> Result<json::Value, json::Error> {
+    ///
+    /// Errors are client errors: 400 Bad Request, or 415 Unsupported Media Type if the Content-Type is wrong.
+    pub fn json(&self) -> Result<json::Value, Error> {
         let body = try!(self.body());
 
         match self.headers().get::<ContentType>() {
             Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => {
-                json::from_slice(body)
+                json::from_slice(body).map_err(|err| Error::wrap(Status::BadRequest, err))
             }
-            Some(_) => Err(json::Error::Io(IoError::new(ErrorKind::InvalidInput, "invalid Content-Type, expected application/json"))),
-            None => Err(json::Error::Io(IoError::new(ErrorKind::InvalidInput, "missing Content-Type header")))
+            Some(_) => Err(Error::new(Status::UnsupportedMediaType, Some(Cow::Borrowed("invalid Content-Type, expected application/json")))),
+            None => Err(Error::new(Status::BadRequest, Some(Cow::Borrowed("missing Content-Type header"))))
         }
     }
 
@@ -139,6 +148,36 @@ impl Request {
         self.query.as_ref().map_or(None, |map| map.get(key).map(String::as_str))
     }
 
+    /// Returns the instant by which the handler must respond (if any), see `Timeouts::handler`.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the time left before the deadline (if any), zero if it has passed.
+    ///
+    /// A handler doing a long computation can check this to give up once its response would be discarded anyway.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            let now = Instant::now();
+            if now < deadline { deadline - now } else { Duration::from_secs(0) }
+        })
+    }
+
+    /// Returns `true` if this request was received over a secure (HTTPS) connection.
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Returns the scheme of this request, `https` if it is secure and `http` otherwise.
+    pub fn scheme(&self) -> &'static str {
+        if self.secure { "https" } else { "http" }
+    }
+
+    /// Returns the URL of this request (unless it is `*`).
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
+
     /// Returns the fragment of this request (if any).
     pub fn fragment(&self) -> Option<&str> {
         match self.url {
@@ -148,6 +187,11 @@ impl Request {
     }
 }
 
+/// Sets the instant by which the handler must respond.
+pub fn set_deadline(request: &mut Request, deadline: Instant) {
+    request.deadline = Some(deadline);
+}
+
 /// Sets the parameters declared by the route that matched the URL of this request.
 pub fn set_params(request: &mut Request, params: BTreeMap<String, String>) {
     request.params = Some(params);
@@ -1,12 +1,22 @@
-use hyper::header::{self, CookiePair as Cookie, ContentType, Header, SetCookie};
+use handlebars::RenderError;
+
+use hyper::header::{self, CookiePair as Cookie, ContentType, EntityTag, Header, SetCookie};
 use hyper::status::StatusCode as Status;
 
 use hyper::Headers;
 use hyper::mime::{Mime, TopLevel, SubLevel, Attr, Value};
 
+use serde::Serialize;
+
+use serde_json;
 use serde_json::value as json;
 use serde_json::value::ToJson;
 
+use url::ParseError;
+
+use assets::EmbeddedFile;
+use templates::EngineError;
+
 use std::any::Any;
 use std::boxed::Box;
 use std::borrow::Cow;
@@ -14,23 +24,49 @@ use std::{error, fmt, result};
 use std::fs::File;
 use std::io::{self, ErrorKind, Read, Write};
 use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
 
 /// Defines a handler error
+///
+/// An error has a status and an optional message, which are sent to the client,
+/// and optionally the underlying error that caused it, which is only logged server-side.
 #[derive(Debug)]
 pub struct Error {
     pub status: Status,
-    pub message: Option<Cow<'static, str>>
+    pub message: Option<Cow<'static, str>>,
+    pub headers: Headers,
+    cause: Option<Box<error::Error + Send + Sync>>
 }
 
 pub type Result = result::Result<Action, Error>;
 
 impl Error {
-    fn new(status: Status, message: Option<Cow<'static, str>>) -> Error {
+    /// Creates an error with the given status and optional message.
+    pub fn new(status: Status, message: Option<Cow<'static, str>>) -> Error {
         Error {
             status: status,
-            message: message
+            message: message,
+            headers: Headers::new(),
+            cause: None
         }
     }
+
+    /// Creates an error with the given status, caused by the given error.
+    pub fn wrap<E>(status: Status, cause: E) -> Error where E: Into<Box<error::Error + Send + Sync>> {
+        Error::new(status, None).with_cause(cause)
+    }
+
+    /// Sets the underlying error that caused this error.
+    pub fn with_cause<E>(mut self, cause: E) -> Error where E: Into<Box<error::Error + Send + Sync>> {
+        self.cause = Some(cause.into());
+        self
+    }
+
+    /// Sets the given header on the response sent for this error.
+    pub fn header<H: Header>(mut self, header: H) -> Error {
+        self.headers.set(header);
+        self
+    }
 }
 
 impl fmt::Display for Error {
@@ -49,7 +85,7 @@ impl error::Error for Error {
     }
 
     fn cause(&self) -> Option<&error::Error> {
-        None
+        self.cause.as_ref().map(|cause| &**cause as &error::Error)
     }
 }
 
@@ -71,6 +107,46 @@ impl From<(Status, String)> for Error {
     }
 }
 
+/// Conversion from `io::Error`, as a 500 Internal Server Error.
+///
+/// An I/O error is a fault of the server (a missing file, a database error), not of the client;
+/// use `Error::wrap` to choose another status.
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::wrap(Status::InternalServerError, err)
+    }
+}
+
+/// Conversion from `json::Error`, as a 500 Internal Server Error.
+///
+/// Errors parsing the body of the request are 400 Bad Request, see `Request::json`.
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::wrap(Status::InternalServerError, err)
+    }
+}
+
+/// Conversion from `RenderError`, as a 500 Internal Server Error.
+impl From<RenderError> for Error {
+    fn from(err: RenderError) -> Error {
+        Error::wrap(Status::InternalServerError, err)
+    }
+}
+
+/// Conversion from the error of a template engine, as a 500 Internal Server Error.
+impl From<EngineError> for Error {
+    fn from(err: EngineError) -> Error {
+        Error::wrap(Status::InternalServerError, err)
+    }
+}
+
+/// Conversion from `url::ParseError`, as a 400 Bad Request.
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::wrap(Status::BadRequest, err)
+    }
+}
+
 /// Defines the action to be taken when returning from a handler
 pub enum Action {
     /// Ends the response with no body and the given status (if given).
@@ -90,9 +166,24 @@ pub enum Action {
     /// Sends the response with the given bytes as the body.
     Send(Vec<u8>),
 
+    /// Sends the given serialized JSON as the body.
+    ///
+    /// If no Content-Type header is set, the content type is set to `application/json; charset=utf-8`.
+    Json(Vec<u8>),
+
+    /// Sends the given serialized JSON wrapped in a call to the given JavaScript function (JSONP).
+    ///
+    /// The content type is set to `application/javascript; charset=utf-8`.
+    Jsonp(String, Vec<u8>),
+
     /// Returns a closure that is called with a Stream argument.
     Stream(Box<Fn(&mut Any, &mut Write)>),
 
+    /// Returns a closure that sends Server-Sent Events, see `event_stream`.
+    ///
+    /// The Content-Type is set to `text/event-stream`, and caching is disabled.
+    EventStream(Box<Fn(&mut Any, &mut Write)>),
+
     /// Sends the given file, setting the Content-Type based on the file's extension.
     ///
     /// Known extensions are:
@@ -101,7 +192,19 @@ pub enum Action {
     ///   - text: css, htm, html, txt
     ///   - video: avi, mp4, mpg, mpeg, ts
     /// If the file does not exist, this method sends a 404 Not Found response.
-    SendFile(String)
+    SendFile(String),
+
+    /// Renders the given Markdown file as an HTML page.
+    ///
+    /// The page is rendered within the layout that applies to the response (if any),
+    /// using the options set by `Edge::set_markdown_options`.
+    /// If the file does not exist, this sends a 404 Not Found response.
+    Markdown(String),
+
+    /// Sends the given file embedded in the binary, see `Assets`.
+    ///
+    /// The Content-Type is set in the same way as `SendFile`.
+    SendEmbedded(EmbeddedFile)
 }
 
 /// Conversion from `()` into `End(None)`.
@@ -169,10 +272,10 @@ impl From<String> for Action {
     }
 }
 
-/// Conversion from `json::Value` into `Action::Send(bytes)`.
+/// Conversion from `json::Value` into `Action::Json(bytes)`.
 impl From<json::Value> for Action {
     fn from(json: json::Value) -> Action {
-        From::from(json.to_string())
+        Action::Json(json.to_string().into_bytes())
     }
 }
 
@@ -191,6 +294,36 @@ pub fn stream<F, T, R>(closure: F) -> Result where T: Any, F: 'static + Fn(&mut
     })))
 }
 
+/// Serializes the given value and returns `Ok(Action::Json(bytes))`.
+///
+/// Returns a 500 Internal Server Error if the value cannot be serialized.
+pub fn send_json<T: Serialize>(value: &T) -> Result {
+    serde_json::to_vec(value).map(Action::Json).map_err(serialization_error)
+}
+
+/// Same as `send_json` except that the JSON is pretty-printed.
+pub fn send_json_pretty<T: Serialize>(value: &T) -> Result {
+    serde_json::to_vec_pretty(value).map(Action::Json).map_err(serialization_error)
+}
+
+/// Serializes the given value and returns `Ok(Action::Jsonp(callback, bytes))`.
+///
+/// The callback is typically given by the client in the query, so it is checked to be
+/// a valid JavaScript identifier (possibly with dots); otherwise a 400 Bad Request is returned.
+pub fn send_jsonp<T: Serialize>(callback: &str, value: &T) -> Result {
+    let valid = !callback.is_empty() && callback.chars().all(|c|
+        c.is_alphanumeric() || c == '_' || c == '$' || c == '.');
+    if !valid {
+        return Err(Error::new(Status::BadRequest, Some(Cow::Borrowed("invalid JSONP callback name"))));
+    }
+
+    serde_json::to_vec(value).map(|bytes| Action::Jsonp(callback.to_string(), bytes)).map_err(serialization_error)
+}
+
+fn serialization_error(err: serde_json::Error) -> Error {
+    Error::wrap(Status::InternalServerError, err)
+}
+
 /// This represents the response that will be sent back to the application.
 ///
 /// Includes a status code (default 200 OK), headers, and a body.
@@ -201,7 +334,22 @@ pub fn stream<F, T, R>(closure: F) -> Result where T: Any, F: 'static + Fn(&mut
 pub struct Response {
     pub status: Status,
     pub headers: Headers,
-    streaming: bool
+    streaming: bool,
+    heartbeat: Option<Duration>,
+    layout: Layout
+}
+
+/// The layout used when rendering a template.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Layout {
+    /// Use the layout of the router, or the default layout.
+    Inherit,
+
+    /// Use the layout with the given name.
+    Named(String),
+
+    /// Do not use any layout.
+    Disabled
 }
 
 impl Response {
@@ -210,7 +358,9 @@ impl Response {
         Response {
             status: Status::Ok,
             headers: Headers::default(),
-            streaming: false
+            streaming: false,
+            heartbeat: None,
+            layout: Layout::Inherit
         }
     }
 
@@ -253,12 +403,35 @@ impl Response {
         self
     }
 
+    /// Sets the layout used to render the template of this response, overriding the layout
+    /// of the router and the default layout.
+    pub fn layout<S: Into<String>>(&mut self, name: S) -> &mut Self {
+        self.layout = Layout::Named(name.into());
+        self
+    }
+
+    /// Renders the template of this response without any layout.
+    pub fn no_layout(&mut self) -> &mut Self {
+        self.layout = Layout::Disabled;
+        self
+    }
+
     /// Sets the Location header.
     pub fn location<S: Into<String>>(&mut self, url: S) -> &mut Self {
         self.headers.set(header::Location(url.into()));
         self
     }
 
+    /// Sets the Content-Type header based on the extension of the given path,
+    /// unless a Content-Type is already set.
+    fn set_content_type_from_path(&mut self, path: &Path) {
+        if !self.headers.has::<ContentType>() {
+            if let Some(content_type) = content_type_from_path(path) {
+                self.headers.set(content_type);
+            }
+        }
+    }
+
     /// Sends the given file, setting the Content-Type based on the file's extension.
     ///
     /// Known extensions are:
@@ -267,56 +440,27 @@ impl Response {
     ///   - text: css, htm, html, txt
     ///   - video: avi, mp4, mpg, mpeg, ts
     /// If the file does not exist, this method sends a 404 Not Found response.
+    ///
+    /// A weak ETag is computed from the file's size and modification time.
     fn send_file<P: AsRef<Path>>(&mut self, path: P) -> Option<Vec<u8>> {
-        if !self.headers.has::<ContentType>() {
-            let extension = path.as_ref().extension();
-            if let Some(ext) = extension {
-                let content_type = match ext.to_string_lossy().as_ref() {
-                    // application
-                    "js" => Some(("application", "javascript", None)),
-                    "m3u8" => Some(("application", "vnd.apple.mpegurl", None)),
-                    "mpd" => Some(("application", "dash+xml", None)),
-                    "xml" => Some(("application", "xml", None)),
-
-                    // image
-                    "gif" => Some(("image", "gif", None)),
-                    "jpg" | "jpeg" => Some(("image", "jpeg", None)),
-                    "png" => Some(("image", "png", None)),
-
-                    // text
-                    "css" => Some(("text", "css", None)),
-                    "htm" | "html" => Some(("text", "html", Some((Attr::Charset, Value::Utf8)))),
-                    "txt" => Some(("text", "plain", Some((Attr::Charset, Value::Utf8)))),
-
-                    // video
-                    "avi" => Some(("video", "x-msvideo", None)),
-                    "mp4" => Some(("video", "mp4", None)),
-                    "mpg" | "mpeg" => Some(("video", "mpeg", None)),
-                    "ts" => Some(("video", "mp2t", None)),
-                    _ => None
-                };
-
-                if let Some((top, sub, attr)) = content_type {
-                    self.headers.set(ContentType(Mime(TopLevel::Ext(top.to_string()),
-                        SubLevel::Ext(sub.to_string()),
-                        match attr {
-                            None => vec![],
-                            Some(val) => vec![val]
-                        }
-                    )));
-                }
-            }
-        }
+        self.set_content_type_from_path(path.as_ref());
 
         // read the whole file at once and send it
         // probably not the best idea for big files, we should use stream instead in that case
         match File::open(path) {
             Ok(mut file) => {
-                let mut buf = Vec::with_capacity(file.metadata().ok().map_or(1024, |meta| meta.len() as usize));
+                let metadata = file.metadata().ok();
+                let mut buf = Vec::with_capacity(metadata.as_ref().map_or(1024, |meta| meta.len() as usize));
                 if let Err(err) = file.read_to_end(&mut buf) {
                     self.status(Status::InternalServerError).content_type("text/plain");
                     Some(format!("{}", err).into())
                 } else {
+                    if let Some(modified) = metadata.and_then(|meta| meta.modified().ok()) {
+                        if let Ok(since_epoch) = modified.duration_since(UNIX_EPOCH) {
+                            let tag = format!("{:x}-{:x}", buf.len(), since_epoch.as_secs());
+                            self.headers.set(header::ETag(EntityTag::weak(tag)));
+                        }
+                    }
                     Some(buf)
                 }
             },
@@ -331,12 +475,66 @@ impl Response {
         }
     }
 
+    /// Sends the given embedded file, setting the Content-Type based on its path's extension
+    /// and the ETag computed when the file was embedded, in the same way as `send_file`.
+    fn send_embedded(&mut self, file: &EmbeddedFile) -> Vec<u8> {
+        self.set_content_type_from_path(Path::new(file.path));
+        self.headers.set(header::ETag(EntityTag::strong(file.etag.clone())));
+        file.content.to_vec()
+    }
+
+}
+
+/// Returns the content type corresponding to the extension of the given path (if known).
+fn content_type_from_path(path: &Path) -> Option<ContentType> {
+    let extension = path.extension();
+    if let Some(ext) = extension {
+        let content_type = match ext.to_string_lossy().as_ref() {
+            // application
+            "js" => Some(("application", "javascript", None)),
+            "m3u8" => Some(("application", "vnd.apple.mpegurl", None)),
+            "mpd" => Some(("application", "dash+xml", None)),
+            "xml" => Some(("application", "xml", None)),
+
+            // image
+            "gif" => Some(("image", "gif", None)),
+            "jpg" | "jpeg" => Some(("image", "jpeg", None)),
+            "png" => Some(("image", "png", None)),
+
+            // text
+            "css" => Some(("text", "css", None)),
+            "htm" | "html" => Some(("text", "html", Some((Attr::Charset, Value::Utf8)))),
+            "txt" => Some(("text", "plain", Some((Attr::Charset, Value::Utf8)))),
+
+            // video
+            "avi" => Some(("video", "x-msvideo", None)),
+            "mp4" => Some(("video", "mp4", None)),
+            "mpg" | "mpeg" => Some(("video", "mpeg", None)),
+            "ts" => Some(("video", "mp2t", None)),
+            _ => None
+        };
+
+        content_type.map(|(top, sub, attr)|
+            ContentType(Mime(TopLevel::Ext(top.to_string()),
+                SubLevel::Ext(sub.to_string()),
+                match attr {
+                    None => vec![],
+                    Some(val) => vec![val]
+                }
+            )))
+    } else {
+        None
+    }
 }
 
 pub fn send_file<P: AsRef<Path>>(response: &mut Response, path: P) -> Option<Vec<u8>> {
     response.send_file(path)
 }
 
+pub fn send_embedded(response: &mut Response, file: &EmbeddedFile) -> Vec<u8> {
+    response.send_embedded(file)
+}
+
 pub fn set_streaming(response: &mut Response) {
     response.streaming = true;
 }
@@ -344,3 +542,25 @@ pub fn set_streaming(response: &mut Response) {
 pub fn is_streaming(response: &Response) -> bool {
     response.streaming
 }
+
+/// Sets the interval after which a heartbeat is sent on a streaming response if nothing else was.
+pub fn set_heartbeat(response: &mut Response, interval: Duration) {
+    response.heartbeat = Some(interval);
+}
+
+pub fn get_heartbeat(response: &Response) -> Option<Duration> {
+    response.heartbeat
+}
+
+/// Sets the given layout if the response does not specify one.
+pub fn inherit_layout(response: &mut Response, layout: Option<&str>) {
+    if response.layout == Layout::Inherit {
+        if let Some(layout) = layout {
+            response.layout = Layout::Named(layout.to_string());
+        }
+    }
+}
+
+pub fn get_layout(response: &Response) -> &Layout {
+    &response.layout
+}
@@ -6,19 +6,24 @@ use hyper::method::Method::{Delete, Get, Head, Post, Put};
 use std::any::Any;
 use std::collections::{BTreeMap, HashMap};
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 use request;
 use request::Request;
 use response::{Result, Response};
+use websocket::WebSocket;
 
+use std::io;
 use std::result;
+use std::time::Duration;
 
 pub type TypedCallback<T> = fn(&mut T, &Request, &mut Response) -> Result;
 pub type TypedMiddleware<T> = fn(&mut T, &mut Request, &mut Response);
 pub type Static = fn(&Request, &mut Response) -> Result;
+pub type TypedWebSocket<T> = fn(&mut T, &Request, &mut WebSocket) -> io::Result<()>;
 
 /// A segment is either a fixed string, or a variable with a name
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum Segment {
     Fixed(String),
     Variable(String)
@@ -38,7 +43,8 @@ impl Segment {
 /// A segment that begins with a colon declares a variable, for example "/:user_id".
 pub struct Route {
     segments: Vec<Segment>,
-    callback: Callback
+    callback: Arc<Callback>,
+    timeout: Option<Duration>
 }
 
 /// Returns a vector of segments from the given string.
@@ -63,9 +69,15 @@ impl Route {
     fn new(from: &str, callback: Callback) -> result::Result<Route, &str> {
         Ok(Route {
             segments: try!(get_segments(from)),
-            callback: callback
+            callback: Arc::new(callback),
+            timeout: None
         })
     }
+
+    /// Returns the callback of this route, shared with the handler thread that calls it.
+    pub fn callback(&self) -> Arc<Callback> {
+        self.callback.clone()
+    }
 }
 
 use std::fmt::{self, Debug, Formatter};
@@ -83,18 +95,61 @@ pub struct Router<T> {
 }
 
 impl<T: Default + Any + Send> Router<T> {
-    /// wraps the value returned by Default::default into a box
-    fn create() -> Box<Any + Send> {
-        Box::new(T::default())
-    }
-
     /// Creates a new router.
+    ///
+    /// An instance of `T` is created for each request by calling `Default::default`.
     pub fn new() -> Router<T> {
         Router {
-            inner: RouterAny::new::<T>(),
+            inner: RouterAny::new(Box::new(|| Box::new(T::default()))),
             _marker: PhantomData
         }
     }
+}
+
+impl<T: Any + Send> Router<T> {
+    /// Creates a new router with the given shared state.
+    ///
+    /// An instance of `T` is created for each request from a clone of the shared state,
+    /// so that handlers can access database pools or configuration without globals.
+    /// Since `Arc<S>` converts from itself, `T` can also be `Arc<S>`, in which case
+    /// handlers are given the shared state directly.
+    pub fn with_state<S>(state: Arc<S>) -> Router<T> where S: Send + Sync + 'static, T: From<Arc<S>> {
+        Router {
+            inner: RouterAny::new(Box::new(move || Box::new(T::from(state.clone())))),
+            _marker: PhantomData
+        }
+    }
+
+    /// Sets the layout used to render templates for this router's routes.
+    ///
+    /// This overrides the default layout set on Edge.
+    pub fn set_layout(&mut self, name: &str) {
+        self.inner.layout = Some(name.to_string());
+    }
+
+    /// Sets how long the handlers of this router's routes may take to produce a response.
+    ///
+    /// This overrides the handler timeout set on Edge, see `Timeouts::handler`.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.timeout = Some(timeout);
+    }
+
+    /// Sets how long the handler of the route registered for the given method and path may take
+    /// to produce a response, overriding the timeout of this router.
+    ///
+    /// Returns an error if the path is invalid, or if no route is registered for it.
+    pub fn set_route_timeout<'a>(&mut self, method: Method, path: &'a str, timeout: Duration) -> result::Result<(), &'a str> {
+        let segments = try!(get_segments(path));
+        let route = self.inner.routes.get_mut(&method).and_then(|routes|
+            routes.iter_mut().find(|route| route.segments == segments));
+        match route {
+            Some(route) => {
+                route.timeout = Some(timeout);
+                Ok(())
+            }
+            None => Err("no route registered for this method and path")
+        }
+    }
 
     pub fn add_middleware(&mut self, middleware: TypedMiddleware<T>) {
         self.inner.middleware.push(Box::new(move |any, req, res| {
@@ -134,6 +189,21 @@ impl<T: Default + Any + Send> Router<T> {
         self.insert(Head, path, callback)
     }
 
+    /// Registers a WebSocket callback for the given path.
+    ///
+    /// The route answers GET requests that carry a WebSocket opening handshake;
+    /// the callback is called once the handshake is complete, and the connection is closed when it returns.
+    /// Middleware is not run for WebSocket routes.
+    ///
+    /// Each connection occupies a handler thread while it is open, see the `websocket` timeout of `Timeouts`;
+    /// one that would take the last thread of the pool is refused with 503 Service Unavailable.
+    pub fn websocket(&mut self, path: &str, callback: TypedWebSocket<T>) {
+        self.insert_callback(Get, path, Callback::WebSocket(Box::new(move |any, req, ws| {
+            let app = any.downcast_mut::<T>().unwrap();
+            callback(app, req, ws)
+        })))
+    }
+
     /// Registers a static callback for the given path for GET requests.
     #[inline]
     pub fn get_static(&mut self, path: &str, callback: Static) {
@@ -170,32 +240,42 @@ pub fn get_inner<T>(router: Router<T>) -> RouterAny {
 
 /// Signature for a callback method
 pub enum Callback {
-    Instance(Box<Fn(&mut Any, &Request, &mut Response) -> Result + Sync>),
-    Static(Static)
+    Instance(Box<Fn(&mut Any, &Request, &mut Response) -> Result + Send + Sync>),
+    Static(Static),
+    WebSocket(Box<Fn(&mut Any, &Request, &mut WebSocket) -> io::Result<()> + Send + Sync>)
 }
 
-pub type Middleware = Box<Fn(&mut Any, &mut Request, &mut Response) + Sync>;
+pub type Middleware = Box<Fn(&mut Any, &mut Request, &mut Response) + Send + Sync>;
+
+/// Creates the instance given to the handlers of a router for one request.
+pub type Init = Box<Fn() -> Box<Any + Send> + Send + Sync>;
 
 /// Router structure
 pub struct RouterAny {
-    init: fn() -> Box<Any + Send>,
+    init: Init,
     prefix: Vec<Segment>,
+    listeners: Vec<String>,
+    layout: Option<String>,
+    timeout: Option<Duration>,
     middleware: Vec<Middleware>,
     routes: HashMap<Method, Vec<Route>>
 }
 
 impl RouterAny {
-    pub fn new<T: Default + Any + Send>() -> RouterAny {
+    pub fn new(init: Init) -> RouterAny {
         RouterAny {
-            init: Router::<T>::create,
+            init: init,
             prefix: Vec::new(),
+            listeners: Vec::new(),
+            layout: None,
+            timeout: None,
             middleware: Vec::new(),
             routes: HashMap::new()
         }
     }
 
-    /// Finds the first route (if any) that matches the given path, and returns the associated callback.
-    pub fn find_callback(&self, req: &mut Request) -> Option<&Callback> {
+    /// Finds the first route (if any) that matches the given path.
+    pub fn find_route(&self, req: &mut Request) -> Option<&Route> {
         if self.match_prefix(req.path()) {
             debug!("{} {:?} matches prefix {:?}", req.method(), req.path(), self.prefix);
         } else {
@@ -205,23 +285,11 @@ impl RouterAny {
 
         if let Some(routes) = self.routes.get(req.method()) {
             let mut params = BTreeMap::new();
-            let prefix_len = self.prefix.len();
-
-            'top: for ref route in routes {
-                let mut it_route = route.segments.iter();
-                for actual in &req.path()[prefix_len..] {
-                    match it_route.next() {
-                        Some(&Segment::Fixed(ref fixed)) if fixed != actual => continue 'top,
-                        Some(&Segment::Variable(ref name)) => {
-                            params.insert(name.to_owned(), actual.to_string());
-                        },
-                        _ => ()
-                    }
-                }
 
-                if it_route.next().is_none() {
+            for route in routes {
+                if self.match_route(route, req.path(), &mut params) {
                     request::set_params(req, params);
-                    return Some(&route.callback);
+                    return Some(route);
                 }
 
                 params.clear();
@@ -235,6 +303,42 @@ impl RouterAny {
         None
     }
 
+    /// Returns the methods for which a route matches the path of the given request.
+    ///
+    /// This is used to respond with 405 Method Not Allowed rather than 404 Not Found.
+    pub fn allowed_methods(&self, req: &Request) -> Vec<Method> {
+        if !self.match_prefix(req.path()) {
+            return Vec::new();
+        }
+
+        let mut params = BTreeMap::new();
+        self.routes.iter().filter_map(|(method, routes)|
+            if routes.iter().any(|route| self.match_route(route, req.path(), &mut params)) {
+                Some(method.clone())
+            } else {
+                None
+            }
+        ).collect()
+    }
+
+    /// Returns `true` if the given route matches the given path (after this router's prefix).
+    ///
+    /// Variables declared by the route are inserted into `params`.
+    fn match_route(&self, route: &Route, path: &[String], params: &mut BTreeMap<String, String>) -> bool {
+        let mut it_route = route.segments.iter();
+        for actual in &path[self.prefix.len()..] {
+            match it_route.next() {
+                Some(&Segment::Fixed(ref fixed)) if fixed != actual => return false,
+                Some(&Segment::Variable(ref name)) => {
+                    params.insert(name.to_owned(), actual.to_string());
+                },
+                _ => ()
+            }
+        }
+
+        it_route.next().is_none()
+    }
+
     /// Returns `true` if the given path matches this router's prefix.
     fn match_prefix(&self, path: &[String]) -> bool {
         if path.len() >= self.prefix.len() {
@@ -249,6 +353,25 @@ impl RouterAny {
         }
     }
 
+    /// Returns `true` if this router serves requests received by the listener with the given name.
+    pub fn serves(&self, listener: &str) -> bool {
+        self.listeners.is_empty() || self.listeners.iter().any(|name| name == listener)
+    }
+
+    /// Restricts this router to the listeners with the given names.
+    pub fn set_listeners(&mut self, listeners: Vec<String>) {
+        self.listeners = listeners;
+    }
+
+    /// Returns the timeout of the handler of the given route (if any).
+    pub fn timeout(&self, route: &Route) -> Option<Duration> {
+        route.timeout.or(self.timeout)
+    }
+
+    pub fn layout(&self) -> Option<&str> {
+        self.layout.as_ref().map(String::as_str)
+    }
+
     pub fn new_instance(&self) -> Box<Any + Send> {
         (self.init)()
     }
@@ -0,0 +1,654 @@
+//! Server handle, used to shut down a running Edge application.
+//!
+//! Shutdown is graceful: once it is requested, new requests are answered with
+//! 503 Service Unavailable and `Connection: close`, while requests in progress (including
+//! streams and WebSocket connections) are given until the shutdown timeout to complete.
+//! Then the remaining connections are closed, and `Edge::start` returns.
+
+use hyper;
+use hyper::net::{Accept, Blocked, HttpListener, HttpStream, Transport};
+#[cfg(feature = "tls")]
+use hyper::net::Openssl;
+
+use url::Url;
+
+use access_log::AccessLog;
+
+#[cfg(feature = "tls")]
+use tls::{self, TlsConfig};
+
+#[cfg(unix)]
+use unix::UnixListener;
+
+use mio::{EventSet, Evented, PollOpt, Selector, Token};
+use mio::tcp::TcpListener;
+
+use vecio::Writev;
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval at which shutdown conditions are checked.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// Describes how the requests received by a listener are handled.
+pub struct Endpoint {
+    /// The name of the listener, used to restrict routers to some listeners.
+    pub name: String,
+
+    /// The base URL of requests, its scheme tells whether connections are secure.
+    pub base_url: Url,
+
+    /// The base URL of the HTTPS listener to redirect requests to (if any).
+    pub redirect: Option<Url>,
+
+    /// Timeouts of connections.
+    pub timeouts: Timeouts,
+
+    /// Maximum number of requests per connection (if any).
+    pub max_requests: Option<usize>,
+
+    /// Whether connections can be taken over by WebSocket handlers, see `Bound::supports_websocket`.
+    pub websocket: bool,
+
+    /// The access log (if any).
+    pub access_log: Option<Arc<AccessLog>>
+}
+
+/// Timeouts of connections, none by default except for WebSocket connections.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Time allowed to receive a request head, from its first bytes; the connection is closed when it expires.
+    ///
+    /// It is checked whenever data is received, a client that sends nothing is stopped by the `keep_alive` timeout.
+    pub header_read: Option<Duration>,
+
+    /// Time allowed between two reads of the request body; the client is sent 408 Request Timeout when it expires.
+    pub body_read: Option<Duration>,
+
+    /// Time allowed between two writes of the response; the connection is closed when it expires.
+    pub write: Option<Duration>,
+
+    /// Time an idle connection is kept alive, waiting for the next request.
+    ///
+    /// It also applies to new connections, and between two reads of a request head.
+    pub keep_alive: Option<Duration>,
+
+    /// Time allowed to handlers to produce a response, see `Router::set_timeout`.
+    ///
+    /// When it expires, the client is sent 503 Service Unavailable, and the response
+    /// of the handler is discarded; handlers can check `Request::remaining` to give up early.
+    /// A handler cannot be interrupted though: one that timed out keeps its pool thread until it returns.
+    pub handler: Option<Duration>,
+
+    /// Time a WebSocket connection may stay without receiving data, 5 minutes by default.
+    ///
+    /// Each WebSocket connection occupies a handler thread, so idle connections should not be kept forever.
+    pub websocket: Option<Duration>
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            header_read: None,
+            body_read: None,
+            write: None,
+            keep_alive: None,
+            handler: None,
+            websocket: Some(Duration::from_secs(300))
+        }
+    }
+}
+
+/// A named listener to bind in addition to the address given to `Edge::new`.
+pub struct Listener {
+    pub name: String,
+    pub kind: Kind
+}
+
+/// The kind of an additional listener.
+pub enum Kind {
+    /// An HTTP listener on the address of the given URL.
+    Http(Url),
+
+    #[cfg(feature = "tls")]
+    Https(Url, TlsConfig),
+
+    /// An already open TCP listening socket, for example passed by systemd.
+    #[cfg(unix)]
+    Fd(RawFd),
+
+    /// A Unix domain socket at the given path, with the given permissions (if any).
+    #[cfg(unix)]
+    Unix(PathBuf, Option<u32>)
+}
+
+/// A bound listener.
+pub enum Bound {
+    Http(HttpListener),
+    #[cfg(feature = "tls")]
+    Https(HttpListener, Openssl),
+    #[cfg(unix)]
+    Unix(UnixListener)
+}
+
+impl Bound {
+    /// Returns `true` if connections accepted by this listener can be taken over by WebSocket handlers,
+    /// which is only the case of plain TCP connections.
+    pub fn supports_websocket(&self) -> bool {
+        match *self {
+            Bound::Http(_) => true,
+            #[cfg(feature = "tls")]
+            Bound::Https(_, _) => false,
+            #[cfg(unix)]
+            Bound::Unix(_) => false
+        }
+    }
+
+    /// Returns a listener that accepts connections on the same socket.
+    pub fn try_clone(&self) -> io::Result<Bound> {
+        match *self {
+            Bound::Http(ref listener) => listener.try_clone().map(Bound::Http).map_err(into_io_error),
+            #[cfg(feature = "tls")]
+            Bound::Https(ref listener, ref ssl) => listener.try_clone()
+                .map(|listener| Bound::Https(listener, ssl.clone()))
+                .map_err(into_io_error),
+            #[cfg(unix)]
+            Bound::Unix(ref listener) => listener.try_clone().map(Bound::Unix)
+        }
+    }
+}
+
+/// Binds a listener on the address of the given URL, and updates its port with the port actually bound.
+pub fn bind(base_url: &mut Url) -> io::Result<HttpListener> {
+    let addr = try!(try!(base_url.to_socket_addrs()).next().ok_or_else(||
+        io::Error::new(ErrorKind::InvalidInput, format!("could not resolve {}", base_url))));
+    let listener = try!(HttpListener::bind(&addr).map_err(into_io_error));
+    let local_addr = try!(listener.local_addr());
+
+    let _ = base_url.set_port(Some(local_addr.port()));
+    Ok(listener)
+}
+
+/// Binds the given listener, updating its URL with the port actually bound.
+pub fn bind_listener(listener: Listener) -> io::Result<(Url, Bound)> {
+    match listener.kind {
+        Kind::Http(mut base_url) => {
+            let listener = try!(bind(&mut base_url));
+            Ok((base_url, Bound::Http(listener)))
+        }
+        #[cfg(feature = "tls")]
+        Kind::Https(mut base_url, config) => {
+            let listener = try!(bind(&mut base_url));
+            Ok((base_url, Bound::Https(listener, tls::into_openssl(config))))
+        }
+        #[cfg(unix)]
+        Kind::Fd(fd) => {
+            use std::net;
+            use std::os::unix::io::FromRawFd;
+
+            // do not leak the socket to child processes
+            if unsafe { ::libc::fcntl(fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+            let addr = try!(listener.local_addr());
+            let listener = try!(TcpListener::from_listener(listener, &addr));
+            let base_url = Url::parse(&format!("http://{}", addr)).unwrap();
+            Ok((base_url, Bound::Http(HttpListener(listener))))
+        }
+        #[cfg(unix)]
+        Kind::Unix(path, mode) => {
+            let listener = try!(UnixListener::bind(&path, mode));
+            let base_url = Url::parse("http://localhost").unwrap();
+            Ok((base_url, Bound::Unix(listener)))
+        }
+    }
+}
+
+/// Returns the listening sockets passed by systemd socket activation, see `sd_listen_fds(3)`.
+///
+/// The `LISTEN_PID` and `LISTEN_FDS` environment variables are removed, so that
+/// child processes do not try to use these sockets.
+#[cfg(unix)]
+pub fn systemd_fds() -> Vec<RawFd> {
+    use std::env;
+
+    // file descriptors passed by systemd start after stdin, stdout, and stderr
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+
+    let own_pid = unsafe { ::libc::getpid() } as u32;
+    match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == own_pid => (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds).collect(),
+        _ => Vec::new()
+    }
+}
+
+/// Returns the address of the given listener.
+pub fn local_addr(listener: &HttpListener) -> io::Result<SocketAddr> {
+    listener.local_addr()
+}
+
+thread_local!(static PEER: RefCell<Option<Rc<Peer>>> = RefCell::new(None));
+
+/// Returns the connection last read on the current thread.
+///
+/// Hyper parses a request head right after reading it, on the thread of the event loop,
+/// so in `Handler::on_request` this is the connection the request was received on.
+pub fn peer() -> Option<Rc<Peer>> {
+    PEER.with(|peer| peer.borrow().clone())
+}
+
+/// State of a connection accepted by a `PeerListener`, shared with the handlers of its requests.
+pub struct Peer {
+    addr: Option<SocketAddr>,
+    requests: Cell<usize>,
+    in_request: Cell<bool>,
+    head_started: Cell<Option<Instant>>
+}
+
+impl Peer {
+    /// Returns the address of the client, if the connection is a plain TCP one.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// Returns the number of requests received on this connection so far.
+    pub fn requests(&self) -> usize {
+        self.requests.get()
+    }
+
+    /// Records that the head of a request was parsed.
+    pub fn request_started(&self) {
+        self.requests.set(self.requests.get() + 1);
+        self.in_request.set(true);
+        self.head_started.set(None);
+    }
+
+    /// Records that a request is complete, so that the next bytes read start a new request head.
+    pub fn request_finished(&self) {
+        self.in_request.set(false);
+    }
+}
+
+/// A listener whose connections share their state with the handlers of their requests, see `peer`,
+/// and are closed when a request head is not received in time.
+pub struct PeerListener<A> {
+    listener: A,
+    header_read: Option<Duration>
+}
+
+impl<A> PeerListener<A> {
+    /// Wraps the given listener, with the given timeout to receive a request head (if any).
+    pub fn new(listener: A, header_read: Option<Duration>) -> PeerListener<A> {
+        PeerListener {
+            listener: listener,
+            header_read: header_read
+        }
+    }
+}
+
+impl<A: Evented> Evented for PeerListener<A> {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.listener.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.listener.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.listener.deregister(selector)
+    }
+}
+
+impl<A: Accept> Accept for PeerListener<A> where A::Output: Any {
+    type Output = PeerStream<A::Output>;
+
+    fn accept(&self) -> io::Result<Option<PeerStream<A::Output>>> {
+        let header_read = self.header_read;
+        self.listener.accept().map(|stream| stream.map(|stream| {
+            let addr = (&stream as &Any).downcast_ref::<HttpStream>().and_then(|stream| stream.0.peer_addr().ok());
+            PeerStream {
+                stream: stream,
+                peer: Rc::new(Peer {
+                    addr: addr,
+                    requests: Cell::new(0),
+                    in_request: Cell::new(false),
+                    head_started: Cell::new(None)
+                }),
+                header_read: header_read
+            }
+        }))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// A connection accepted by a `PeerListener`.
+pub struct PeerStream<T> {
+    stream: T,
+    peer: Rc<Peer>,
+    header_read: Option<Duration>
+}
+
+impl<T> PeerStream<T> {
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+impl<T: Read> Read for PeerStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        PEER.with(|peer| *peer.borrow_mut() = Some(self.peer.clone()));
+        let len = try!(self.stream.read(buf));
+
+        // the idle timer of Hyper does not bound the time taken by a client
+        // that sends its request head a few bytes at a time
+        if len > 0 && !self.peer.in_request.get() {
+            match self.peer.head_started.get() {
+                None => self.peer.head_started.set(Some(Instant::now())),
+                Some(started) => {
+                    if self.header_read.map_or(false, |header_read| started.elapsed() > header_read) {
+                        info!("timed out reading request head");
+                        return Err(io::Error::new(ErrorKind::TimedOut, "timed out reading request head"));
+                    }
+                }
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for PeerStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<T: Writev> Writev for PeerStream<T> {
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.stream.writev(bufs)
+    }
+}
+
+impl<T: Evented> Evented for PeerStream<T> {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.stream.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.stream.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.stream.deregister(selector)
+    }
+}
+
+impl<T: Transport> Transport for PeerStream<T> {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.stream.take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        self.stream.blocked()
+    }
+}
+
+/// Converts a hyper error to an I/O error.
+pub fn into_io_error(err: hyper::Error) -> io::Error {
+    match err {
+        hyper::Error::Io(err) => err,
+        err => io::Error::new(ErrorKind::Other, err)
+    }
+}
+
+/// A handle to a running Edge application, obtained with `Edge::handle`.
+///
+/// The handle can be cloned and sent to other threads, for example by an integration test
+/// that starts a server in a thread and stops it when done.
+#[derive(Clone)]
+pub struct ServerHandle {
+    inner: Arc<Inner>
+}
+
+/// State shared by the server threads.
+struct Inner {
+    shutdown: AtomicBool,
+    closing: AtomicBool,
+    in_flight: AtomicUsize,
+    sockets: Mutex<(usize, HashMap<usize, TcpStream>)>,
+
+    // number of handler jobs waiting for a thread of the pool, and their limit (0 if unlimited)
+    pending_jobs: AtomicUsize,
+    max_pending_jobs: AtomicUsize,
+
+    // number of WebSocket connections, each occupying a thread of the pool, and the size of the pool
+    websockets: AtomicUsize,
+    pool_size: AtomicUsize
+}
+
+impl ServerHandle {
+    /// Requests the server to shut down.
+    ///
+    /// This method returns immediately; `Edge::start` returns once shutdown is complete.
+    pub fn shutdown(&self) {
+        if !self.inner.shutdown.swap(true, Ordering::SeqCst) {
+            info!("shutdown requested");
+        }
+    }
+
+    /// Returns `true` if shutdown has been requested.
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.shutdown.load(Ordering::SeqCst)
+    }
+}
+
+pub fn new() -> ServerHandle {
+    ServerHandle {
+        inner: Arc::new(Inner {
+            shutdown: AtomicBool::new(false),
+            closing: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            sockets: Mutex::new((0, HashMap::new())),
+            pending_jobs: AtomicUsize::new(0),
+            max_pending_jobs: AtomicUsize::new(0),
+            websockets: AtomicUsize::new(0),
+            pool_size: AtomicUsize::new(0)
+        })
+    }
+}
+
+/// Clears the state left by a previous run, so that the server can be started again.
+pub fn reset(handle: &ServerHandle) {
+    let inner = &handle.inner;
+    inner.shutdown.store(false, Ordering::SeqCst);
+    inner.closing.store(false, Ordering::SeqCst);
+    inner.in_flight.store(0, Ordering::SeqCst);
+    *inner.sockets.lock().unwrap() = (0, HashMap::new());
+    inner.pending_jobs.store(0, Ordering::SeqCst);
+    inner.websockets.store(0, Ordering::SeqCst);
+}
+
+/// Returns `true` if the remaining connections are being closed.
+pub fn is_closing(handle: &ServerHandle) -> bool {
+    handle.inner.closing.load(Ordering::SeqCst)
+}
+
+/// Records the start of a request.
+pub fn request_started(handle: &ServerHandle) {
+    handle.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Records the end of a request.
+pub fn request_finished(handle: &ServerHandle) {
+    handle.inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Sets the maximum number of handler jobs waiting for a thread, 0 for no limit.
+pub fn set_max_pending_jobs(handle: &ServerHandle, max: usize) {
+    handle.inner.max_pending_jobs.store(max, Ordering::SeqCst);
+}
+
+/// Sets the number of threads of the handler pool.
+pub fn set_pool_size(handle: &ServerHandle, pool_size: usize) {
+    handle.inner.pool_size.store(pool_size, Ordering::SeqCst);
+}
+
+/// Records a handler job waiting for a thread, unless the limit is reached,
+/// in which case this returns `false`.
+pub fn job_queued(handle: &ServerHandle) -> bool {
+    let max = handle.inner.max_pending_jobs.load(Ordering::SeqCst);
+    let pending = handle.inner.pending_jobs.fetch_add(1, Ordering::SeqCst);
+    if max > 0 && pending >= max {
+        handle.inner.pending_jobs.fetch_sub(1, Ordering::SeqCst);
+        false
+    } else {
+        true
+    }
+}
+
+/// Records that a handler job started running.
+pub fn job_started(handle: &ServerHandle) {
+    handle.inner.pending_jobs.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Records a WebSocket job waiting for a thread, as `job_queued` does, and the connection
+/// that will occupy this thread until `websocket_closed` is called.
+///
+/// This returns `false` if WebSocket connections would then occupy every thread of the pool,
+/// which is always left one thread for other requests.
+pub fn websocket_queued(handle: &ServerHandle) -> bool {
+    let pool_size = handle.inner.pool_size.load(Ordering::SeqCst);
+    let websockets = handle.inner.websockets.fetch_add(1, Ordering::SeqCst) + 1;
+    if websockets >= pool_size || !job_queued(handle) {
+        handle.inner.websockets.fetch_sub(1, Ordering::SeqCst);
+        false
+    } else {
+        true
+    }
+}
+
+pub fn websocket_closed(handle: &ServerHandle) {
+    handle.inner.websockets.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Registers a socket taken over from the server (such as a WebSocket connection)
+/// so that it can be closed when the shutdown timeout expires.
+///
+/// The socket counts as a request in progress until it is unregistered.
+pub fn register_socket(handle: &ServerHandle, stream: &TcpStream) -> Option<usize> {
+    match stream.try_clone() {
+        Ok(stream) => {
+            request_started(handle);
+            let mut sockets = handle.inner.sockets.lock().unwrap();
+            let id = sockets.0;
+            sockets.0 += 1;
+            sockets.1.insert(id, stream);
+            Some(id)
+        }
+        Err(e) => {
+            error!("could not register socket: {}", e);
+            None
+        }
+    }
+}
+
+/// Unregisters the socket with the given id.
+pub fn unregister_socket(handle: &ServerHandle, id: usize) {
+    handle.inner.sockets.lock().unwrap().1.remove(&id);
+    request_finished(handle);
+}
+
+/// Blocks until shutdown is requested (by the handle or a signal), or until no server is `running`,
+/// then waits until requests in progress complete or `timeout` expires.
+///
+/// When this function returns, streams and registered sockets are being closed.
+/// Returns `false` if requests were still in progress when the timeout expired.
+pub fn wait(handle: &ServerHandle, running: &AtomicUsize, signals: bool, timeout: Duration) -> bool {
+    let interval = Duration::from_millis(POLL_INTERVAL_MS);
+    while !handle.is_shutting_down() {
+        if running.load(Ordering::SeqCst) == 0 {
+            break;
+        }
+
+        if signals && signal_received() {
+            info!("received termination signal");
+            handle.shutdown();
+        } else {
+            thread::sleep(interval);
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    while handle.inner.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        thread::sleep(interval);
+    }
+
+    let in_flight = handle.inner.in_flight.load(Ordering::SeqCst);
+    if in_flight > 0 {
+        warn!("closing {} requests still in progress after shutdown timeout", in_flight);
+    }
+
+    handle.inner.closing.store(true, Ordering::SeqCst);
+    for (_, stream) in handle.inner.sockets.lock().unwrap().1.drain() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    in_flight == 0
+}
+
+static SIGNALED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Returns `true` if SIGTERM or SIGINT was received.
+fn signal_received() -> bool {
+    SIGNALED.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: ::libc::c_int) {
+    SIGNALED.store(true, Ordering::SeqCst);
+}
+
+/// Installs handlers for SIGTERM and SIGINT that request shutdown.
+///
+/// A signal received during a previous run is forgotten.
+#[cfg(unix)]
+pub fn install_signal_handlers() {
+    use libc;
+
+    SIGNALED.store(false, Ordering::SeqCst);
+    unsafe {
+        libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() {
+}
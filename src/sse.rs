@@ -0,0 +1,148 @@
+//! Server-Sent Events support.
+//!
+//! See the [EventSource specification](https://html.spec.whatwg.org/multipage/comms.html#server-sent-events)
+//! for details about the `text/event-stream` format.
+//!
+//! Proxies tend to close connections on which nothing is received for a while, so a comment
+//! is sent to the client whenever no event was sent for `HEARTBEAT_INTERVAL` seconds,
+//! even if the producer is blocked (for example waiting on a channel or a database).
+
+use response::{Action, Result};
+
+use std::any::Any;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval between two heartbeats, in seconds.
+pub const HEARTBEAT_INTERVAL: u64 = 15;
+
+/// A typed writer for a `text/event-stream` response.
+///
+/// Fields are accumulated with `event`, `data`, `id` and `retry` until `send` is called,
+/// which dispatches the event to the client. When the client has disconnected,
+/// `send` (as well as `heartbeat` and `sleep`) returns an error, so that the producer loop can stop.
+pub struct EventStream<'a> {
+    writer: &'a mut Write,
+    pending: Vec<u8>,
+    heartbeat: Duration,
+    last_write: Instant
+}
+
+impl<'a> EventStream<'a> {
+    fn new(writer: &'a mut Write) -> EventStream<'a> {
+        EventStream {
+            writer: writer,
+            pending: Vec::new(),
+            heartbeat: Duration::from_secs(HEARTBEAT_INTERVAL),
+            last_write: Instant::now()
+        }
+    }
+
+    /// Sets the interval after which `heartbeat` and `sleep` send a heartbeat if nothing else was written.
+    ///
+    /// Heartbeats are sent anyway every `HEARTBEAT_INTERVAL` seconds, this is only useful for a shorter interval.
+    pub fn heartbeat_interval(&mut self, interval: Duration) -> &mut Self {
+        self.heartbeat = interval;
+        self
+    }
+
+    /// Sets the type of the event being built.
+    pub fn event(&mut self, name: &str) -> &mut Self {
+        self.field("event", name)
+    }
+
+    /// Appends the given data to the event being built.
+    ///
+    /// Data spanning multiple lines is sent as multiple `data` fields.
+    pub fn data(&mut self, data: &str) -> &mut Self {
+        for line in data.lines() {
+            self.field("data", line);
+        }
+        if data.is_empty() {
+            self.field("data", "");
+        }
+        self
+    }
+
+    /// Sets the id of the event being built; the client sends it back in `Last-Event-ID` when reconnecting.
+    pub fn id(&mut self, id: &str) -> &mut Self {
+        self.field("id", id)
+    }
+
+    /// Sets the reconnection time the client should use.
+    pub fn retry(&mut self, retry: Duration) -> &mut Self {
+        let millis = retry.as_secs() * 1000 + (retry.subsec_nanos() / 1_000_000) as u64;
+        self.field("retry", &millis.to_string())
+    }
+
+    /// Dispatches the event built so far to the client.
+    pub fn send(&mut self) -> io::Result<()> {
+        self.pending.push(b'\n');
+        let pending = ::std::mem::replace(&mut self.pending, Vec::new());
+        self.write(&pending)
+    }
+
+    /// Sends a comment to keep the connection alive if nothing was written during the heartbeat interval.
+    pub fn heartbeat(&mut self) -> io::Result<()> {
+        if self.last_write.elapsed() >= self.heartbeat {
+            self.write(b":\n\n")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sleeps for the given duration, sending heartbeats as needed in the meantime.
+    pub fn sleep(&mut self, duration: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            try!(self.heartbeat());
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+
+            let next_heartbeat = self.last_write + self.heartbeat;
+            let wake_up = if next_heartbeat < deadline { next_heartbeat } else { deadline };
+            if wake_up > now {
+                thread::sleep(wake_up - now);
+            }
+        }
+    }
+
+    fn field(&mut self, name: &str, value: &str) -> &mut Self {
+        // line breaks are not allowed within a field
+        let value = value.replace(|c| c == '\r' || c == '\n', "");
+        self.pending.extend_from_slice(name.as_bytes());
+        self.pending.extend_from_slice(b": ");
+        self.pending.extend_from_slice(value.as_bytes());
+        self.pending.push(b'\n');
+        self
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        try!(self.writer.write_all(buf));
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+/// Wraps the given closure in a box and returns `Ok(Action::EventStream(box))`.
+///
+/// The response is sent with a `text/event-stream` content type, and the closure
+/// is called with an `EventStream` used to send events to the client.
+pub fn event_stream<F, T, R>(closure: F) -> Result where T: Any, F: 'static + Fn(&mut T, &mut EventStream) -> io::Result<R> {
+    Ok(Action::EventStream(Box::new(move |any, writer| {
+        if let Some(app) = any.downcast_mut::<T>() {
+            let mut events = EventStream::new(writer);
+            if let Err(e) = closure(app, &mut events) {
+                if e.kind() == io::ErrorKind::BrokenPipe {
+                    debug!("event stream closed by client");
+                } else {
+                    error!("{}", e);
+                }
+            }
+        }
+    })))
+}
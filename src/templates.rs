@@ -0,0 +1,383 @@
+//! Templates module, wraps the Handlebars registry.
+//!
+//! In development mode, template files are checked for modification before rendering,
+//! and those that changed are registered again.
+//!
+//! A template can be rendered within a layout: the layout is given the same data as the template,
+//! plus the rendered template as `body`, and the blocks filled by the template with the `content`
+//! helper as `blocks`. For example, a template containing `{{#content "title"}}Users{{/content}}`
+//! can be rendered in a layout containing `<title>{{#block "title"}}Default title{{/block}}</title>`
+//! and `<body>{{{body}}}</body>`.
+//!
+//! Templates are rendered by a `TemplateEngine`, Handlebars by default. Layouts are rendered
+//! by the same engine, and are given `body` and `blocks` whatever the engine; but the `content`
+//! and `block` helpers are Handlebars helpers, so with another engine, only `body` is filled.
+
+use handlebars::{Handlebars, HelperDef, TemplateError, TemplateFileError};
+
+use serde_json::value as json;
+
+use helpers;
+use markdown::MarkdownOptions;
+
+use std::{error, fmt, fs, io};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+/// An error that occurred while loading templates.
+#[derive(Debug)]
+pub enum TemplateLoadError {
+    /// A directory could not be read.
+    Io(io::Error),
+
+    /// The template with the given name could not be read or parsed.
+    Template(String, TemplateFileError),
+
+    /// The template with the given name, registered from a string, could not be parsed.
+    Parse(String, TemplateError)
+}
+
+impl fmt::Display for TemplateLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TemplateLoadError::Io(ref err) => write!(f, "could not read templates: {}", err),
+            TemplateLoadError::Template(ref name, ref err) => write!(f, "could not load template {}: {}", name, err),
+            TemplateLoadError::Parse(ref name, ref err) => write!(f, "could not parse template {}: {}", name, err)
+        }
+    }
+}
+
+impl error::Error for TemplateLoadError {
+    fn description(&self) -> &str {
+        match *self {
+            TemplateLoadError::Io(_) => "could not read templates",
+            TemplateLoadError::Template(_, _) => "could not load template",
+            TemplateLoadError::Parse(_, _) => "could not parse template"
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            TemplateLoadError::Io(ref err) => Some(err),
+            TemplateLoadError::Template(_, ref err) => Some(err),
+            TemplateLoadError::Parse(_, ref err) => Some(err)
+        }
+    }
+}
+
+impl From<io::Error> for TemplateLoadError {
+    fn from(err: io::Error) -> TemplateLoadError {
+        TemplateLoadError::Io(err)
+    }
+}
+
+/// A template engine, used to render templates for `Action::Render`.
+///
+/// This trait is implemented for closures, so a simple engine can be written as:
+///
+/// ```ignore
+/// edge.set_template_engine(Box::new(|name: &str, data: &json::Value| {
+///     Ok(format!("{}: {}", name, data))
+/// }));
+/// ```
+pub trait TemplateEngine: Send + Sync {
+    /// Renders the template with the given name using the given data.
+    fn render(&self, name: &str, data: &json::Value) -> Result<String, EngineError>;
+}
+
+/// An error returned by a template engine.
+pub type EngineError = Box<error::Error + Send + Sync>;
+
+impl<F> TemplateEngine for F where F: Fn(&str, &json::Value) -> Result<String, EngineError> + Send + Sync {
+    fn render(&self, name: &str, data: &json::Value) -> Result<String, EngineError> {
+        self(name, data)
+    }
+}
+
+/// A template registered from a file.
+struct TemplateFile {
+    name: String,
+    path: PathBuf,
+    modified: Option<SystemTime>
+}
+
+/// Returns `true` if the given path has the `hbs` extension.
+fn is_template(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "hbs")
+}
+
+/// Returns the last modification time of the file at the given path.
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// The Handlebars template engine, with templates registered from files or strings.
+pub struct HandlebarsEngine {
+    handlebars: RwLock<Handlebars>,
+
+    // when both are needed, `files` is always locked before `handlebars`
+    files: Mutex<Vec<TemplateFile>>,
+    reload: AtomicBool,
+    custom_markdown: AtomicBool
+}
+
+/// The Handlebars engine, shared between `Templates` (to register templates) and the engine in use.
+struct SharedHandlebars(Arc<HandlebarsEngine>);
+
+impl TemplateEngine for SharedHandlebars {
+    fn render(&self, name: &str, data: &json::Value) -> Result<String, EngineError> {
+        self.0.render(name, data)
+    }
+}
+
+pub struct Templates {
+    handlebars: Arc<HandlebarsEngine>,
+    engine: Box<TemplateEngine>,
+    layout: Option<String>,
+    markdown: MarkdownOptions
+}
+
+impl Templates {
+    pub fn new(handlebars: Handlebars) -> Templates {
+        let handlebars = Arc::new(HandlebarsEngine {
+            handlebars: RwLock::new(handlebars),
+            files: Mutex::new(Vec::new()),
+            reload: AtomicBool::new(false),
+            custom_markdown: AtomicBool::new(false)
+        });
+
+        Templates {
+            handlebars: handlebars.clone(),
+            engine: Box::new(SharedHandlebars(handlebars)),
+            layout: None,
+            markdown: MarkdownOptions::default()
+        }
+    }
+
+    /// Sets the engine used to render templates instead of Handlebars.
+    pub fn set_engine(&mut self, engine: Box<TemplateEngine>) {
+        self.engine = engine;
+    }
+
+    /// Returns the Handlebars engine, used to register templates and helpers.
+    pub fn handlebars(&self) -> &HandlebarsEngine {
+        &self.handlebars
+    }
+
+    /// Sets the default layout.
+    pub fn set_default_layout(&mut self, layout: Option<String>) {
+        self.layout = layout;
+    }
+
+    /// Returns the name of the default layout (if any).
+    pub fn default_layout(&self) -> Option<&str> {
+        self.layout.as_ref().map(String::as_str)
+    }
+
+    /// Sets the options used to render Markdown, and registers the built-in `markdown` helper again with them,
+    /// unless it was replaced by a custom helper.
+    pub fn set_markdown_options(&mut self, options: MarkdownOptions) {
+        self.markdown = options;
+        if !self.handlebars.custom_markdown.load(Ordering::SeqCst) {
+            self.handlebars.handlebars.write().unwrap().register_helper("markdown", helpers::markdown_helper(options));
+        }
+    }
+
+    /// Returns the options used to render Markdown.
+    pub fn markdown_options(&self) -> &MarkdownOptions {
+        &self.markdown
+    }
+
+    /// Renders the template with the given name using the given data, with the template engine.
+    pub fn render(&self, name: &str, data: &json::Value) -> Result<String, EngineError> {
+        self.engine.render(name, data)
+    }
+
+    /// Renders the template with the given name using the given data, within the given layout (if any).
+    pub fn render_with_layout(&self, name: &str, data: &json::Value, layout: Option<&str>) -> Result<String, EngineError> {
+        let layout = match layout {
+            None => return self.render(name, data),
+            Some(layout) => layout
+        };
+
+        helpers::clear_blocks();
+        let body = try!(self.render(name, data));
+        self.render_layout(layout, data, body)
+    }
+
+    /// Renders the given layout with the given data, the given body, and the blocks filled on this thread.
+    pub fn render_layout(&self, layout: &str, data: &json::Value, body: String) -> Result<String, EngineError> {
+        let mut layout_data = match *data {
+            json::Value::Object(ref map) => map.clone(),
+            _ => BTreeMap::new()
+        };
+        layout_data.insert("body".to_string(), json::Value::String(body));
+        layout_data.insert("blocks".to_string(), json::Value::Object(helpers::blocks().into_iter()
+            .map(|(name, block)| (name, json::Value::String(block)))
+            .collect()));
+
+        let result = self.render(layout, &json::Value::Object(layout_data));
+        helpers::clear_blocks();
+        result
+    }
+}
+
+impl HandlebarsEngine {
+    /// Enables or disables reloading of modified template files.
+    pub fn set_reload(&self, reload: bool) {
+        self.reload.store(reload, Ordering::SeqCst);
+    }
+
+    /// Registers the template file at the given path with the given name.
+    pub fn register_file(&self, name: &str, path: &Path) -> Result<(), TemplateLoadError> {
+        let modified = modified(path);
+        let mut files = self.files.lock().unwrap();
+        try!(self.handlebars.write().unwrap().register_template_file(name, path).map_err(|err|
+            TemplateLoadError::Template(name.to_string(), err)));
+
+        files.retain(|file| file.name != name);
+        files.push(TemplateFile {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            modified: modified
+        });
+        Ok(())
+    }
+
+    /// Registers a template with the given name from the given source.
+    ///
+    /// Templates registered from strings are not reloaded, and replace any template file
+    /// registered with the same name.
+    pub fn register_string(&self, name: &str, source: &str) -> Result<(), TemplateLoadError> {
+        let mut files = self.files.lock().unwrap();
+        try!(self.handlebars.write().unwrap().register_template_string(name, source.to_string()).map_err(|err|
+            TemplateLoadError::Parse(name.to_string(), err)));
+
+        files.retain(|file| file.name != name);
+        Ok(())
+    }
+
+    /// Registers the given helper.
+    pub fn register_helper(&self, name: &str, helper: Box<HelperDef + 'static>) {
+        if name == "markdown" {
+            self.custom_markdown.store(true, Ordering::SeqCst);
+        }
+        self.handlebars.write().unwrap().register_helper(name, helper);
+    }
+
+    /// Registers the `.hbs` files found in the given directory (not recursively),
+    /// named after their file stem.
+    pub fn register_flat(&self, dir: &Path) -> Result<(), TemplateLoadError> {
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            if is_template(&path) {
+                let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+                try!(self.register_file(&name, &path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers all `.hbs` files found in the given directory and its subdirectories,
+    /// except those in the `exclude` directory (if any).
+    ///
+    /// Templates are named after their path relative to `root` without the extension,
+    /// for example `admin/users/list` for `<root>/admin/users/list.hbs`.
+    /// Symbolic links to directories are followed, but each directory is visited only once.
+    pub fn register_recursive(&self, root: &Path, dir: &Path, exclude: Option<&Path>) -> Result<(), TemplateLoadError> {
+        let mut visited = HashSet::new();
+        self.register_dir(root, dir, exclude, &mut visited)
+    }
+
+    fn register_dir(&self, root: &Path, dir: &Path, exclude: Option<&Path>, visited: &mut HashSet<PathBuf>) -> Result<(), TemplateLoadError> {
+        // guards against symbolic link loops
+        if !visited.insert(try!(fs::canonicalize(dir))) {
+            return Ok(());
+        }
+
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            if try!(fs::metadata(&path)).is_dir() {
+                if Some(path.as_path()) != exclude {
+                    try!(self.register_dir(root, &path, exclude, visited));
+                }
+            } else if is_template(&path) {
+                let relative = path.strip_prefix(root).unwrap().with_extension("");
+                let name = relative.components()
+                    .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                try!(self.register_file(&name, &path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Unregisters the templates registered from files in the given directory (not recursively).
+    pub fn unregister_dir(&self, dir: &Path) {
+        let mut files = self.files.lock().unwrap();
+        let mut handlebars = self.handlebars.write().unwrap();
+        files.retain(|file| {
+            if file.path.parent() == Some(dir) {
+                handlebars.unregister_template(&file.name);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Registers again the template files that were modified since they were last registered.
+    ///
+    /// All modified templates are registered while holding the write lock,
+    /// so that a render never sees a partially updated set of templates and partials.
+    fn reload_modified(&self) {
+        let mut files = self.files.lock().unwrap();
+        let modified_files: Vec<usize> = files.iter().enumerate()
+            .filter(|&(_, file)| modified(&file.path) != file.modified)
+            .map(|(index, _)| index)
+            .collect();
+        if modified_files.is_empty() {
+            return;
+        }
+
+        let mut handlebars = self.handlebars.write().unwrap();
+        let mut deleted = Vec::new();
+        for index in modified_files {
+            let file = &mut files[index];
+            let current = modified(&file.path);
+            if current.is_none() {
+                // the file was deleted, the template stays registered as it was
+                warn!("template {} was deleted from {}, it will not be reloaded", file.name, file.path.display());
+                deleted.push(index);
+                continue;
+            }
+
+            info!("reloading template {} from {}", file.name, file.path.display());
+            if let Err(e) = handlebars.register_template_file(&file.name, &file.path) {
+                error!("could not reload template {}: {}", file.name, e);
+            }
+
+            // a template that could not be parsed is reloaded once it is modified again
+            file.modified = current;
+        }
+
+        for index in deleted.into_iter().rev() {
+            files.remove(index);
+        }
+    }
+}
+
+/// Handlebars is the default template engine.
+impl TemplateEngine for HandlebarsEngine {
+    fn render(&self, name: &str, data: &json::Value) -> Result<String, EngineError> {
+        if self.reload.load(Ordering::SeqCst) {
+            self.reload_modified();
+        }
+
+        self.handlebars.read().unwrap().render(name, data).map_err(|err| Box::new(err) as EngineError)
+    }
+}
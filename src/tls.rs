@@ -0,0 +1,76 @@
+//! TLS configuration for HTTPS listeners, available with the `tls` feature.
+//!
+//! A configuration has a default certificate, and optionally other certificates
+//! selected by the host name the client asks for (Server Name Indication).
+
+use hyper::net::Openssl;
+
+use openssl::ssl::{Ssl, SslContext, SslMethod, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3};
+use openssl::x509::X509FileType;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// value returned by the servername callback to continue the handshake
+const SSL_TLSEXT_ERR_OK: i32 = 0;
+
+/// TLS configuration: certificates and private keys, in PEM format.
+pub struct TlsConfig {
+    default: SslContext,
+    by_hostname: HashMap<String, SslContext>
+}
+
+impl TlsConfig {
+    /// Creates a configuration from the given certificate (chain) and private key files.
+    pub fn new<C: AsRef<Path>, K: AsRef<Path>>(cert: C, key: K) -> io::Result<TlsConfig> {
+        Ok(TlsConfig {
+            default: try!(context(cert.as_ref(), key.as_ref())),
+            by_hostname: HashMap::new()
+        })
+    }
+
+    /// Adds the certificate to use when the client asks for the given host name.
+    pub fn add_certificate<C: AsRef<Path>, K: AsRef<Path>>(&mut self, hostname: &str, cert: C, key: K) -> io::Result<()> {
+        let context = try!(context(cert.as_ref(), key.as_ref()));
+        self.by_hostname.insert(hostname.to_lowercase(), context);
+        Ok(())
+    }
+}
+
+/// Creates an SSL context with the given certificate and private key.
+fn context(cert: &Path, key: &Path) -> io::Result<SslContext> {
+    let mut context = try!(SslContext::new(SslMethod::Sslv23).map_err(into_io_error));
+    // Sslv23 negotiates the highest version supported by both sides, but allows SSLv2 and SSLv3
+    context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3);
+    try!(context.set_certificate_chain_file(cert, X509FileType::PEM).map_err(into_io_error));
+    try!(context.set_private_key_file(key, X509FileType::PEM).map_err(into_io_error));
+    try!(context.check_private_key().map_err(into_io_error));
+    Ok(context)
+}
+
+fn into_io_error<E: Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// Switches to the context of the host name given by the client (if any).
+fn select_context(ssl: &mut Ssl, _alert: &mut i32, by_hostname: &HashMap<String, SslContext>) -> i32 {
+    if let Some(hostname) = ssl.get_servername() {
+        if let Some(context) = by_hostname.get(&hostname.to_lowercase()) {
+            ssl.set_ssl_context(context);
+        }
+    }
+    SSL_TLSEXT_ERR_OK
+}
+
+/// Converts the given configuration for use by an HTTPS listener.
+pub fn into_openssl(config: TlsConfig) -> Openssl {
+    let mut context = config.default;
+    if !config.by_hostname.is_empty() {
+        context.set_servername_callback_with_data(select_context, config.by_hostname);
+    }
+
+    Openssl { context: Arc::new(context) }
+}
@@ -0,0 +1,138 @@
+//! Unix domain socket listener, for use behind a local reverse proxy.
+//!
+//! The listener and its connections wrap those of mio, so that Hyper can serve them like TCP ones.
+//! A Unix domain socket has no network address: the local address reported to Hyper is `0.0.0.0:0`,
+//! and requests received on it have `http://localhost` as base URL.
+
+use hyper::net::{Accept, Transport};
+
+use libc;
+
+use mio::{EventSet, Evented, PollOpt, Selector, Token};
+use mio::unix;
+
+use vecio::Writev;
+
+use std::fs::{self, Permissions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// A listening Unix domain socket.
+pub struct UnixListener(unix::UnixListener);
+
+impl UnixListener {
+    /// Binds a socket at the given path, with the given permissions (if any).
+    ///
+    /// A socket left at this path by a previous run is removed first.
+    pub fn bind(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                try!(fs::remove_file(path));
+            }
+        }
+
+        let listener = try!(unix::UnixListener::bind(&path));
+        if let Some(mode) = mode {
+            try!(fs::set_permissions(path, Permissions::from_mode(mode)));
+        }
+        Ok(UnixListener(listener))
+    }
+
+    /// Returns a listener that accepts connections on the same socket.
+    pub fn try_clone(&self) -> io::Result<UnixListener> {
+        self.0.try_clone().map(UnixListener)
+    }
+}
+
+impl Evented for UnixListener {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.0.deregister(selector)
+    }
+}
+
+impl Accept for UnixListener {
+    type Output = UnixStream;
+
+    fn accept(&self) -> io::Result<Option<UnixStream>> {
+        self.0.accept().map(|stream| stream.map(UnixStream))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)))
+    }
+}
+
+/// A connection accepted by a `UnixListener`.
+pub struct UnixStream(unix::UnixStream);
+
+impl Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Writev for UnixStream {
+    /// Writes the first non-empty buffer, a partial write is allowed.
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        match bufs.iter().find(|buf| !buf.is_empty()) {
+            Some(buf) => self.0.write(buf),
+            None => Ok(0)
+        }
+    }
+}
+
+impl Evented for UnixStream {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.0.deregister(selector)
+    }
+}
+
+impl Transport for UnixStream {
+    /// Returns the pending error of the socket (`SO_ERROR`), if any.
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        let mut error: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(self.0.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ERROR,
+                &mut error as *mut libc::c_int as *mut libc::c_void, &mut len)
+        };
+
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else if error != 0 {
+            Err(io::Error::from_raw_os_error(error))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,542 @@
+//! WebSocket support (RFC 6455).
+//!
+//! A WebSocket route is registered with `Router::websocket`. When a request for that route
+//! carries a valid opening handshake, the HTTP connection is taken over from Hyper,
+//! the handshake is completed, and the handler is called with a `WebSocket`
+//! that sends and receives messages until either side closes the connection.
+//!
+//! Each WebSocket connection occupies a thread of the handler pool (see `Edge::pool_size`)
+//! for as long as it is open; an opening handshake is answered with 503 Service Unavailable
+//! when it would take the last thread of the pool.
+//! Idle connections are closed after the WebSocket timeout, see `Timeouts::websocket`.
+
+use hyper::header::{Connection, ConnectionOption, Upgrade, ProtocolName};
+use hyper::method::Method::Get;
+
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use sha1::Sha1;
+
+use request::Request;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::str;
+use std::time::Duration;
+
+/// GUID used to compute the Sec-WebSocket-Accept header, see RFC 6455 section 1.3.
+const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Maximum size of a message, fragmented or not.
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Time allowed to the peer to answer a close message, in seconds.
+const CLOSE_TIMEOUT_SECS: u64 = 5;
+
+/// A WebSocket message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// A UTF-8 text message.
+    Text(String),
+
+    /// A binary message.
+    Binary(Vec<u8>),
+
+    /// A ping, answered automatically with a pong.
+    Ping(Vec<u8>),
+
+    /// A pong, in response to a ping.
+    Pong(Vec<u8>),
+
+    /// A close message, with an optional status code and reason.
+    Close(Option<(u16, String)>)
+}
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// A WebSocket connection.
+pub struct WebSocket {
+    stream: TcpStream,
+    fragment: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    closed: bool
+}
+
+impl WebSocket {
+    fn new(stream: TcpStream) -> WebSocket {
+        WebSocket {
+            stream: stream,
+            fragment: None,
+            close_sent: false,
+            closed: false
+        }
+    }
+
+    /// Receives the next message.
+    ///
+    /// Fragmented messages are reassembled, even when control messages are interleaved with fragments.
+    /// Pings are answered with a pong before being returned, and a close message is acknowledged
+    /// before being returned; once closed, this method returns an error.
+    ///
+    /// An error of kind `WouldBlock` or `TimedOut` is returned when no data is received
+    /// within the WebSocket timeout (see `Timeouts::websocket`), after which the connection should be dropped.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        if self.closed {
+            return Err(io::Error::new(ErrorKind::NotConnected, "WebSocket is closed"));
+        }
+
+        loop {
+            let (fin, opcode, payload) = try!(self.read_frame());
+            match opcode {
+                OP_PING => {
+                    if !self.close_sent {
+                        try!(self.write_frame(OP_PONG, &payload));
+                    }
+                    return Ok(Message::Ping(payload));
+                }
+                OP_PONG => return Ok(Message::Pong(payload)),
+                OP_CLOSE => {
+                    if payload.len() == 1 {
+                        return self.fail(1002, "invalid close payload");
+                    }
+                    let close = if payload.len() >= 2 {
+                        let code = (payload[0] as u16) << 8 | payload[1] as u16;
+                        Some((code, String::from_utf8_lossy(&payload[2..]).into_owned()))
+                    } else {
+                        None
+                    };
+
+                    // echo the status code back, as required by section 5.5.1,
+                    // unless this is the answer to our own close message
+                    if !self.close_sent {
+                        let echo = if payload.len() >= 2 { &payload[0..2] } else { &[][..] };
+                        try!(self.write_frame(OP_CLOSE, echo));
+                        self.close_sent = true;
+                    }
+                    self.closed = true;
+                    return Ok(Message::Close(close));
+                }
+                OP_TEXT | OP_BINARY if self.fragment.is_none() => {
+                    self.fragment = Some((opcode, payload));
+                }
+                OP_CONTINUATION if self.fragment.is_some() => {
+                    let len = self.fragment.as_ref().unwrap().1.len() as u64 + payload.len() as u64;
+                    if len > MAX_MESSAGE_SIZE {
+                        return self.fail(1009, "message too big");
+                    }
+                    self.fragment.as_mut().unwrap().1.extend_from_slice(&payload);
+                }
+                _ => return self.fail(1002, "unexpected frame")
+            }
+
+            if fin {
+                let (opcode, payload) = self.fragment.take().unwrap();
+                return if opcode == OP_TEXT {
+                    match String::from_utf8(payload) {
+                        Ok(text) => Ok(Message::Text(text)),
+                        Err(_) => self.fail(1007, "invalid UTF-8 in text message")
+                    }
+                } else {
+                    Ok(Message::Binary(payload))
+                };
+            }
+        }
+    }
+
+    /// Sends the given message.
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OP_BINARY, &data),
+            Message::Ping(data) => self.write_frame(OP_PING, &data),
+            Message::Pong(data) => self.write_frame(OP_PONG, &data),
+            Message::Close(None) => self.close(1000, ""),
+            Message::Close(Some((code, reason))) => self.close(code, &reason)
+        }
+    }
+
+    /// Sends a text message.
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(OP_TEXT, text.as_bytes())
+    }
+
+    /// Sends a binary message.
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(OP_BINARY, data)
+    }
+
+    /// Sends a ping with the given payload.
+    pub fn ping(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(OP_PING, data)
+    }
+
+    /// Performs the closing handshake with the given status code and reason.
+    ///
+    /// Sends a close message, then waits for the close message of the peer,
+    /// discarding the messages received in the meantime.
+    /// The peer has a few seconds to answer, after which the connection is given up.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if !self.close_sent {
+            try!(self.send_close(code, reason));
+        }
+
+        // the WebSocket timeout may be long or unset, and would hold the thread meanwhile
+        try!(self.stream.set_read_timeout(Some(Duration::from_secs(CLOSE_TIMEOUT_SECS))));
+        while !self.closed {
+            if let Err(e) = self.recv() {
+                self.closed = true;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the closing handshake has been performed.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Sends a close message, after which no other message can be sent.
+    fn send_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = vec![(code >> 8) as u8, code as u8];
+        payload.extend_from_slice(reason.as_bytes());
+        try!(self.write_frame(OP_CLOSE, &payload));
+        self.close_sent = true;
+        Ok(())
+    }
+
+    /// Fails the connection with the given status code, without waiting for the peer (section 7.1.7).
+    fn fail<T>(&mut self, code: u16, reason: &'static str) -> io::Result<T> {
+        if !self.close_sent {
+            let _ = self.send_close(code, reason);
+        }
+        self.closed = true;
+        Err(io::Error::new(ErrorKind::InvalidData, reason))
+    }
+
+    /// Reads a frame, returns the FIN bit, opcode, and unmasked payload.
+    fn read_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut header = [0u8; 2];
+        try!(self.stream.read_exact(&mut header));
+
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        if header[0] & 0x70 != 0 {
+            return self.fail(1002, "reserved bits must be 0");
+        }
+
+        // all frames sent from client to server have the mask bit set to 1
+        if header[1] & 0x80 == 0 {
+            return self.fail(1002, "client frames must be masked");
+        }
+
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut buf = [0u8; 2];
+                try!(self.stream.read_exact(&mut buf));
+                (buf[0] as u64) << 8 | buf[1] as u64
+            }
+            127 => {
+                let mut buf = [0u8; 8];
+                try!(self.stream.read_exact(&mut buf));
+                buf.iter().fold(0, |len, &b| len << 8 | b as u64)
+            }
+            len => len as u64
+        };
+
+        if opcode >= OP_CLOSE && (!fin || len > 125) {
+            return self.fail(1002, "invalid control frame");
+        }
+        if len > MAX_MESSAGE_SIZE {
+            return self.fail(1009, "message too big");
+        }
+
+        let mut mask = [0u8; 4];
+        try!(self.stream.read_exact(&mut mask));
+
+        let mut payload = vec![0; len as usize];
+        try!(self.stream.read_exact(&mut payload));
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok((fin, opcode, payload))
+    }
+
+    /// Writes an unfragmented, unmasked frame.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(ErrorKind::NotConnected, "WebSocket is closed"));
+        }
+
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+
+        let len = payload.len();
+        if len < 126 {
+            frame.push(len as u8);
+        } else if len <= 0xFFFF {
+            frame.push(126);
+            frame.push((len >> 8) as u8);
+            frame.push(len as u8);
+        } else {
+            frame.push(127);
+            for i in (0..8).rev() {
+                frame.push(((len as u64) >> (i * 8)) as u8);
+            }
+        }
+
+        frame.extend_from_slice(payload);
+        try!(self.stream.write_all(&frame));
+        self.stream.flush()
+    }
+}
+
+/// Checks that the given request is a valid opening handshake, see RFC 6455 section 4.2.1.
+///
+/// Returns the value of the Sec-WebSocket-Accept header to send back.
+pub fn check_handshake(req: &Request) -> Result<String, &'static str> {
+    if *req.method() != Get {
+        return Err("WebSocket handshake must be a GET request");
+    }
+
+    let headers = req.headers();
+    let upgrade = headers.get::<Upgrade>().map_or(false, |&Upgrade(ref protocols)|
+        protocols.iter().any(|protocol| protocol.name == ProtocolName::WebSocket));
+    if !upgrade {
+        return Err("missing Upgrade: websocket header");
+    }
+
+    let connection = headers.get::<Connection>().map_or(false, |&Connection(ref options)|
+        options.iter().any(|option| match *option {
+            ConnectionOption::ConnectionHeader(ref name) => name.eq_ignore_ascii_case("upgrade"),
+            _ => false
+        }));
+    if !connection {
+        return Err("missing Connection: Upgrade header");
+    }
+
+    match headers.get_raw("Sec-WebSocket-Version") {
+        Some(values) if values.len() == 1 && &*values[0] == b"13" => (),
+        _ => return Err("unsupported Sec-WebSocket-Version, expected 13")
+    }
+
+    match headers.get_raw("Sec-WebSocket-Key") {
+        Some(values) if values.len() == 1 => {
+            match str::from_utf8(&values[0]) {
+                Ok(key) => Ok(accept_key(key.trim())),
+                Err(_) => Err("invalid Sec-WebSocket-Key header")
+            }
+        }
+        _ => Err("missing Sec-WebSocket-Key header")
+    }
+}
+
+/// Computes the value of the Sec-WebSocket-Accept header from the client's key.
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    sha1.digest().bytes().to_base64(STANDARD)
+}
+
+/// Completes the opening handshake on the given stream and returns a WebSocket.
+///
+/// Reads time out after the given duration (if any).
+pub fn accept(mut stream: TcpStream, accept: &str, timeout: Option<Duration>) -> io::Result<WebSocket> {
+    try!(stream.set_nonblocking(false));
+    try!(stream.set_read_timeout(timeout));
+    try!(write!(stream, "HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {}\r\n\r\n", accept));
+    try!(stream.flush());
+    Ok(WebSocket::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, WebSocket, OP_BINARY, OP_CLOSE, OP_CONTINUATION, OP_PING, OP_TEXT};
+
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    /// Returns a server-side WebSocket and the client end of its connection.
+    fn pair() -> (WebSocket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (WebSocket::new(server), client)
+    }
+
+    /// Encodes a frame as a client would, masked with the given key (if any).
+    fn frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+
+        let len = payload.len();
+        if len < 126 {
+            frame.push(mask_bit | len as u8);
+        } else if len <= 0xFFFF {
+            frame.push(mask_bit | 126);
+            frame.push((len >> 8) as u8);
+            frame.push(len as u8);
+        } else {
+            frame.push(mask_bit | 127);
+            for i in (0..8).rev() {
+                frame.push(((len as u64) >> (i * 8)) as u8);
+            }
+        }
+
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+            }
+            None => frame.extend_from_slice(payload)
+        }
+        frame
+    }
+
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        frame(fin, opcode, payload, Some([0x37, 0xfa, 0x21, 0x3d]))
+    }
+
+    /// Sends the given bytes from another thread, so that large frames cannot fill the socket buffers.
+    fn send(client: &TcpStream, bytes: Vec<u8>) -> JoinHandle<()> {
+        let mut client = client.try_clone().unwrap();
+        thread::spawn(move || client.write_all(&bytes).unwrap())
+    }
+
+    /// Reads a short frame sent by the server, returns its first byte and its payload.
+    fn read_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header[1] & 0x80, 0, "server frames must not be masked");
+
+        let len = (header[1] & 0x7F) as usize;
+        assert!(len < 126);
+        let mut payload = vec![0; len];
+        client.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+
+    /// Reads a close frame sent by the server, returns its status code.
+    fn read_close_code(client: &mut TcpStream) -> u16 {
+        let (first, payload) = read_frame(client);
+        assert_eq!(first, 0x80 | OP_CLOSE);
+        (payload[0] as u16) << 8 | payload[1] as u16
+    }
+
+    #[test]
+    fn masked_text_frame() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(true, OP_TEXT, b"hello")).unwrap();
+        assert_eq!(ws.recv().unwrap(), Message::Text("hello".to_string()));
+    }
+
+    #[test]
+    fn unmasked_frame_fails_connection() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&frame(true, OP_TEXT, b"hello", None)).unwrap();
+        assert_eq!(ws.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read_close_code(&mut client), 1002);
+        assert!(ws.is_closed());
+    }
+
+    #[test]
+    fn length_on_two_bytes() {
+        let (mut ws, client) = pair();
+        let payload = vec![0xab; 300];
+        let writer = send(&client, masked(true, OP_BINARY, &payload));
+        assert_eq!(ws.recv().unwrap(), Message::Binary(payload));
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn length_on_eight_bytes() {
+        let (mut ws, client) = pair();
+        let payload = (0..70000).map(|i| i as u8).collect::<Vec<u8>>();
+        let writer = send(&client, masked(true, OP_BINARY, &payload));
+        assert_eq!(ws.recv().unwrap(), Message::Binary(payload));
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn control_frame_over_125_bytes_fails_connection() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(true, OP_PING, &[0; 126])).unwrap();
+        assert_eq!(ws.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read_close_code(&mut client), 1002);
+    }
+
+    #[test]
+    fn fragmented_control_frame_fails_connection() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(false, OP_PING, b"ping")).unwrap();
+        assert_eq!(ws.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read_close_code(&mut client), 1002);
+    }
+
+    #[test]
+    fn ping_between_fragments() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(false, OP_TEXT, b"Hel")).unwrap();
+        client.write_all(&masked(true, OP_PING, b"p")).unwrap();
+        client.write_all(&masked(true, OP_CONTINUATION, b"lo")).unwrap();
+
+        assert_eq!(ws.recv().unwrap(), Message::Ping(b"p".to_vec()));
+        assert_eq!(read_frame(&mut client), (0x8A, b"p".to_vec()));
+        assert_eq!(ws.recv().unwrap(), Message::Text("Hello".to_string()));
+    }
+
+    #[test]
+    fn continuation_without_first_fragment_fails_connection() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(true, OP_CONTINUATION, b"lo")).unwrap();
+        assert_eq!(ws.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read_close_code(&mut client), 1002);
+    }
+
+    #[test]
+    fn invalid_utf8_fails_connection() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(true, OP_TEXT, &[0x68, 0xff, 0xfe])).unwrap();
+        assert_eq!(ws.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read_close_code(&mut client), 1007);
+    }
+
+    #[test]
+    fn utf8_character_split_across_fragments() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(false, OP_TEXT, &[0xc3])).unwrap();
+        client.write_all(&masked(true, OP_CONTINUATION, &[0xa9])).unwrap();
+        assert_eq!(ws.recv().unwrap(), Message::Text("\u{e9}".to_string()));
+    }
+
+    #[test]
+    fn close_is_echoed() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(true, OP_CLOSE, &[0x03, 0xe8, b'b', b'y', b'e'])).unwrap();
+        assert_eq!(ws.recv().unwrap(), Message::Close(Some((1000, "bye".to_string()))));
+        assert_eq!(read_close_code(&mut client), 1000);
+        assert!(ws.is_closed());
+        assert_eq!(ws.recv().unwrap_err().kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn one_byte_close_payload_fails_connection() {
+        let (mut ws, mut client) = pair();
+        client.write_all(&masked(true, OP_CLOSE, &[0x03])).unwrap();
+        assert_eq!(ws.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read_close_code(&mut client), 1002);
+    }
+}